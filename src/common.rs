@@ -1,51 +1,274 @@
-use clap::Arg;
+use crate::config::Config;
+use crate::credentials;
+use crate::expect::{self, Expectation};
+use crate::output::{OutputFormat, ResultFormat};
+use crate::retry::Backoff;
+use crate::value::Row;
+use clap::{Arg, ArgGroup};
+#[cfg(feature = "odbc")]
 use odbc_api::handles::Record;
+use std::{collections::HashMap, fmt, str::FromStr, time::Duration};
 use url::ParseError;
 
-#[derive(PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum DbMode {
+    #[cfg(feature = "odbc")]
     Odbc,
+    #[cfg(feature = "postgres")]
     Postgres,
+    #[cfg(feature = "mysql")]
+    Mysql,
+    #[cfg(feature = "mssql")]
+    Mssql,
+    #[cfg(feature = "sqlite")]
+    Sqlite,
 }
 
+#[cfg(not(any(
+    feature = "odbc",
+    feature = "postgres",
+    feature = "mysql",
+    feature = "mssql",
+    feature = "sqlite"
+)))]
+compile_error!(
+    "At least one of the odbc, postgres, mysql, mssql or sqlite features must be enabled"
+);
+
+/// The modes that were compiled in, in the order they're listed in `--help`
+const MODES: &[DbMode] = &[
+    #[cfg(feature = "odbc")]
+    DbMode::Odbc,
+    #[cfg(feature = "postgres")]
+    DbMode::Postgres,
+    #[cfg(feature = "mysql")]
+    DbMode::Mysql,
+    #[cfg(feature = "mssql")]
+    DbMode::Mssql,
+    #[cfg(feature = "sqlite")]
+    DbMode::Sqlite,
+];
+
 impl DbMode {
-    fn from_str(s: &str) -> DbMode {
-        if s.to_lowercase() == "odbc" {
-            DbMode::Odbc
-        } else {
-            DbMode::Postgres
+    pub(crate) fn from_str(s: &str) -> DbMode {
+        let s = s.to_lowercase();
+        MODES
+            .iter()
+            .find(|mode| mode.name() == s)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn names() -> &'static [&'static str] {
+        &[
+            #[cfg(feature = "odbc")]
+            "odbc",
+            #[cfg(feature = "postgres")]
+            "postgres",
+            #[cfg(feature = "mysql")]
+            "mysql",
+            #[cfg(feature = "mssql")]
+            "mssql",
+            #[cfg(feature = "sqlite")]
+            "sqlite",
+        ]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "odbc")]
+            DbMode::Odbc => "odbc",
+            #[cfg(feature = "postgres")]
+            DbMode::Postgres => "postgres",
+            #[cfg(feature = "mysql")]
+            DbMode::Mysql => "mysql",
+            #[cfg(feature = "mssql")]
+            DbMode::Mssql => "mssql",
+            #[cfg(feature = "sqlite")]
+            DbMode::Sqlite => "sqlite",
         }
     }
+
+    /// Whether connection strings for this mode are URLs, rather than ODBC/ADO.NET key/value pairs
+    pub fn uses_url(&self) -> bool {
+        match self {
+            #[cfg(feature = "odbc")]
+            DbMode::Odbc => false,
+            #[cfg(feature = "postgres")]
+            DbMode::Postgres => true,
+            #[cfg(feature = "mysql")]
+            DbMode::Mysql => true,
+            #[cfg(feature = "mssql")]
+            DbMode::Mssql => false,
+            // Just a file path
+            #[cfg(feature = "sqlite")]
+            DbMode::Sqlite => false,
+        }
+    }
+}
+
+impl Default for DbMode {
+    fn default() -> Self {
+        MODES[0].clone()
+    }
 }
 
+/// One database to wait for, from `--target mode=...,url=...,query=...`
+#[derive(Clone, Debug)]
+pub struct Target {
+    pub name: Option<String>,
+    pub mode: DbMode,
+    pub connection_string: String,
+    pub sql_query: Option<String>,
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    // Connection strings and queries can have commas in them, so only split on ",<key>="
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const KEYS: [&str; 4] = ["name", "mode", "url", "query"];
+        let mut starts = vec![0];
+        for (index, _) in s.match_indices(',') {
+            let rest = &s[index + 1..];
+            if KEYS.iter().any(|key| {
+                rest.strip_prefix(key)
+                    .is_some_and(|after| after.starts_with('='))
+            }) {
+                starts.push(index + 1);
+            }
+        }
+        starts.push(s.len() + 1);
+
+        let mut values: HashMap<&str, &str> = HashMap::new();
+        for window in starts.windows(2) {
+            let part = &s[window[0]..window[1] - 1];
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key=value but got '{}'", part))?;
+            if !KEYS.contains(&key) {
+                return Err(format!(
+                    "Unknown target key '{}' (expected one of {})",
+                    key,
+                    KEYS.join(", ")
+                ));
+            }
+            if values.insert(key, value).is_some() {
+                return Err(format!("'{}' given more than once in '{}'", key, s));
+            }
+        }
+
+        let mode = values
+            .get("mode")
+            .ok_or_else(|| format!("No mode given in '{}'", s))?;
+        if !DbMode::names().contains(mode) {
+            return Err(format!(
+                "Unknown mode '{}' (expected one of {})",
+                mode,
+                DbMode::names().join(", ")
+            ));
+        }
+        let connection_string = values
+            .get("url")
+            .filter(|url| !url.is_empty())
+            .ok_or_else(|| format!("No url given in '{}'", s))?;
+        Ok(Target {
+            name: values.get("name").map(|name| name.to_string()),
+            mode: DbMode::from_str(mode),
+            connection_string: connection_string.to_string(),
+            sql_query: values
+                .get("query")
+                .filter(|query| !query.is_empty())
+                .map(|query| query.to_string()),
+        })
+    }
+}
+
+#[derive(Clone)]
 pub struct Opts {
     pub mode: DbMode,
     pub connection_string: String,
     pub sql_query: Option<String>,
-    pub timeout_seconds: Option<u64>,
+    pub timeout: Option<Duration>,
+    pub connect_timeout: Option<Duration>,
+    pub query_timeout: Option<Duration>,
     pub quiet: bool,
-    pub pause_seconds: u64,
+    pub verbose: u8,
+    pub pause: Duration,
+    pub backoff: Backoff,
+    pub max_pause: Option<Duration>,
+    pub ca_file: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub min_rows: usize,
+    pub max_rows: Option<usize>,
+    pub expectations: Vec<Expectation>,
+    pub command: Vec<String>,
+    pub strict: bool,
+    pub output: OutputFormat,
+    /// Prints the query results to stdout once ready, and sends the logging to stderr
+    pub result_format: Option<ResultFormat>,
+    pub targets: Vec<Target>,
+    pub any: bool,
+}
+
+/// Plain numbers are seconds (for backwards compatibility), otherwise humantime-style e.g. `250ms`
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    if let Ok(seconds) = s.parse::<f64>() {
+        return Duration::try_from_secs_f64(seconds).map_err(|e| e.to_string());
+    }
+    humantime::parse_duration(s).map_err(|e| e.to_string())
 }
 
 pub fn parse_args() -> Opts {
     let matches = clap::Command::new("wait-for-db")
         .version(clap::crate_version!())
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                .help("TOML (or YAML for .yaml/.yml) file with options, which are overridden by the command line"),
+        )
         .arg(
             Arg::new("mode")
                 .short('m')
                 .long("mode")
-                .required(true)
-                .value_parser(["odbc", "postgres"])
+                .required_unless_present_any(["target", "config"])
+                .value_parser(clap::builder::PossibleValuesParser::new(
+                    DbMode::names().iter().copied(),
+                ))
                 .help("Database mode"),
         )
         .arg(
             Arg::new("connection-string")
                 .short('c')
                 .long("connection-string")
-                .required(true)
                 .value_parser(clap::builder::NonEmptyStringValueParser::new())
                 .help("Connection string"),
         )
+        .arg(
+            Arg::new("connection-string-env")
+                .long("connection-string-env")
+                .value_name("NAME")
+                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                .conflicts_with("connection-string")
+                .help("Environment variable to read the connection string from"),
+        )
+        .arg(
+            Arg::new("connection-string-file")
+                .long("connection-string-file")
+                .value_name("PATH")
+                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                .conflicts_with_all(["connection-string", "connection-string-env"])
+                .help("File to read the connection string from"),
+        )
+        .arg(
+            Arg::new("password-file")
+                .long("password-file")
+                .value_name("PATH")
+                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                .help("File with the password to add to the connection string"),
+        )
         .arg(
             Arg::new("sql-query")
                 .short('s')
@@ -53,55 +276,356 @@ pub fn parse_args() -> Opts {
                 .value_parser(clap::builder::NonEmptyStringValueParser::new())
                 .help("SQL query that should return at least one row (default: no querying)"),
         )
+        .arg(
+            Arg::new("target")
+                .long("target")
+                .action(clap::ArgAction::Append)
+                .value_parser(|s: &str| s.parse::<Target>())
+                .help("Another database to wait for e.g. 'mode=postgres,url=postgresql://...,query=SELECT 1' (can be repeated)"),
+        )
+        .arg(
+            Arg::new("any")
+                .long("any")
+                .action(clap::ArgAction::SetTrue)
+                .help("Succeed when any of the databases is ready (default: wait for all of them)"),
+        )
+        .group(
+            ArgGroup::new("queries")
+                .args(["sql-query", "target", "config"])
+                .multiple(true),
+        )
+        .arg(
+            Arg::new("min-rows")
+                .long("min-rows")
+                .value_parser(clap::value_parser!(usize))
+                .requires("queries")
+                .help("Minimum number of rows the SQL query should return")
+                .default_value("1"),
+        )
+        .arg(
+            Arg::new("max-rows")
+                .long("max-rows")
+                .value_parser(clap::value_parser!(usize))
+                .requires("queries")
+                .help("Maximum number of rows the SQL query should return (default: no limit)"),
+        )
+        .arg(
+            Arg::new("expect")
+                .short('e')
+                .long("expect")
+                .action(clap::ArgAction::Append)
+                .value_parser(|s: &str| s.parse::<Expectation>())
+                .requires("queries")
+                .help("Expected value in the SQL query results e.g. 'status=done', 'version~^2', 'count>=3' (can be repeated)"),
+        )
         .arg(
             Arg::new("timeout")
                 .short('t')
                 .long("timeout")
-                .value_parser(clap::value_parser!(u64))
-                .help("Timeout in seconds (default: wait forever)"),
+                .value_parser(parse_duration)
+                .help("Timeout e.g. '30', '1.5s', '2m' (plain numbers are seconds) (default: wait forever)"),
+        )
+        .arg(
+            Arg::new("connect-timeout")
+                .long("connect-timeout")
+                .value_parser(parse_duration)
+                .help("Timeout for each connection attempt (default: driver default)"),
+        )
+        .arg(
+            Arg::new("query-timeout")
+                .long("query-timeout")
+                .value_parser(parse_duration)
+                .requires("queries")
+                .help("Timeout for each run of the SQL query (default: driver default)"),
         )
         .arg(
             Arg::new("quiet")
                 .short('q')
                 .long("quiet")
                 .action(clap::ArgAction::SetTrue)
-                .help("Quiet mode"),
+                .conflicts_with("verbose")
+                .help("Quiet mode (only report the final failure)"),
+        )
+        .arg(
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .action(clap::ArgAction::Count)
+                .help("Verbose mode (-v for per-attempt timings, -vv for driver diagnostics)"),
         )
         .arg(
             Arg::new("pause")
                 .short('p')
                 .long("pause")
-                .value_parser(clap::value_parser!(u64))
-                .help("Pause between checks (seconds)")
+                .value_parser(parse_duration)
+                .help("Pause between checks e.g. '3', '250ms' (plain numbers are seconds)")
                 .default_value("3"),
         )
+        .arg(
+            Arg::new("backoff")
+                .short('b')
+                .long("backoff")
+                .value_parser(["fixed", "linear", "exponential", "decorrelated-jitter"])
+                .help("How the pause changes between checks")
+                .default_value("fixed"),
+        )
+        .arg(
+            Arg::new("max-pause")
+                .long("max-pause")
+                .value_parser(parse_duration)
+                .help("Maximum pause between checks (default: no limit)"),
+        )
+        .arg(
+            Arg::new("ca-file")
+                .long("ca-file")
+                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                .help("CA certificate bundle (PEM) for verifying Postgres TLS (default: system roots)"),
+        )
+        .arg(
+            Arg::new("client-cert")
+                .long("client-cert")
+                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                .requires("client-key")
+                .help("Client certificate chain (PEM) for Postgres TLS authentication"),
+        )
+        .arg(
+            Arg::new("client-key")
+                .long("client-key")
+                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                .requires("client-cert")
+                .help("Client private key (PEM) for Postgres TLS authentication"),
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .value_parser(["text", "json"])
+                .help("Output format (json gives one object per line)")
+                .default_value("text"),
+        )
+        .arg(
+            Arg::new("result-format")
+                .long("result-format")
+                .value_parser(["table", "csv", "tsv", "json", "scalar"])
+                .requires("queries")
+                .help("Print the query results to stdout in this format, with logging on stderr (scalar is the value of a one-row, one-column result)"),
+        )
+        .arg(
+            Arg::new("strict")
+                .long("strict")
+                .value_parser(clap::value_parser!(bool))
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_value("true")
+                .default_missing_value("true")
+                .help("Only run the command if the database is ready (--strict=false runs it regardless)"),
+        )
+        .arg(
+            Arg::new("command")
+                .num_args(1..)
+                .last(true)
+                .value_name("COMMAND")
+                .help("Command to run once the database is ready (after --)"),
+        )
         .get_matches();
-    Opts {
-        mode: DbMode::from_str(matches.get_one::<String>("mode").unwrap()),
+    let mut opts = Opts {
+        mode: matches
+            .get_one::<String>("mode")
+            .map(|mode| DbMode::from_str(mode))
+            .unwrap_or_default(),
         connection_string: matches
             .get_one::<String>("connection-string")
-            .unwrap()
-            .to_string(),
+            .cloned()
+            .unwrap_or_default(),
         sql_query: matches.get_one::<String>("sql-query").cloned(),
-        timeout_seconds: matches.get_one::<u64>("timeout").copied(),
-        quiet: matches.contains_id("quiet"),
-        pause_seconds: matches.get_one::<u64>("pause").copied().unwrap(),
+        timeout: matches.get_one::<Duration>("timeout").copied(),
+        connect_timeout: matches.get_one::<Duration>("connect-timeout").copied(),
+        query_timeout: matches.get_one::<Duration>("query-timeout").copied(),
+        quiet: matches.get_flag("quiet"),
+        verbose: matches.get_count("verbose"),
+        pause: matches.get_one::<Duration>("pause").copied().unwrap(),
+        backoff: Backoff::from_str(matches.get_one::<String>("backoff").unwrap()),
+        max_pause: matches.get_one::<Duration>("max-pause").copied(),
+        ca_file: matches.get_one::<String>("ca-file").cloned(),
+        client_cert: matches.get_one::<String>("client-cert").cloned(),
+        client_key: matches.get_one::<String>("client-key").cloned(),
+        min_rows: matches.get_one::<usize>("min-rows").copied().unwrap(),
+        max_rows: matches.get_one::<usize>("max-rows").copied(),
+        expectations: matches
+            .get_many::<Expectation>("expect")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        command: matches
+            .get_many::<String>("command")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        strict: matches.get_one::<bool>("strict").copied().unwrap(),
+        output: OutputFormat::from_str(matches.get_one::<String>("output").unwrap()),
+        result_format: matches
+            .get_one::<String>("result-format")
+            .map(|format| ResultFormat::from_str(format)),
+        targets: matches
+            .get_many::<Target>("target")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        any: matches.get_flag("any"),
+    };
+
+    let mut mode_given = matches.contains_id("mode");
+    if let Some(path) = matches.get_one::<String>("config") {
+        let from_command_line =
+            |id: &str| matches.value_source(id) == Some(clap::parser::ValueSource::CommandLine);
+        match Config::load(path).and_then(|config| {
+            config
+                .apply(&mut opts, from_command_line)
+                .map(|_| config.has_mode())
+        }) {
+            Ok(has_mode) => mode_given |= has_mode,
+            Err(e) => usage_error(&format!("Invalid config file {}: {}", path, e)),
+        }
+    }
+
+    if let Err(e) = resolve_credentials(&matches, &mut opts, mode_given) {
+        usage_error(&e);
+    }
+
+    // Either half of mode/connection string can come from a config file, so check them here
+    if mode_given && opts.connection_string.is_empty() {
+        usage_error("Mode given without a connection string");
+    }
+    if !mode_given && !opts.connection_string.is_empty() {
+        usage_error("Connection string given without a mode");
+    }
+    if opts.connection_string.is_empty() && opts.targets.is_empty() {
+        usage_error("No connection string or targets given");
+    }
+    if opts.sql_query.is_none()
+        && opts.targets.iter().all(|t| t.sql_query.is_none())
+        && (!opts.expectations.is_empty() || opts.max_rows.is_some())
+    {
+        usage_error("Row checks given without an SQL query");
+    }
+    // JSON output already has the rows, and both want stdout to themselves
+    if opts.result_format.is_some() && opts.output == OutputFormat::Json {
+        usage_error("Result format can't be used with JSON output");
     }
+    opts
+}
+
+// Connection strings and passwords that are kept out of the command line
+#[cfg_attr(not(feature = "postgres"), allow(unused_variables))]
+fn resolve_credentials(
+    matches: &clap::ArgMatches,
+    opts: &mut Opts,
+    mode_given: bool,
+) -> Result<(), String> {
+    if let Some(name) = matches.get_one::<String>("connection-string-env") {
+        opts.connection_string = credentials::connection_string_from_env(name)?;
+    }
+    if let Some(path) = matches.get_one::<String>("connection-string-file") {
+        opts.connection_string = credentials::connection_string_from_file(path)?;
+    }
+    #[cfg(feature = "postgres")]
+    if opts.connection_string.is_empty() && mode_given && opts.mode == DbMode::Postgres {
+        if let Some(connection_string) =
+            credentials::postgres_from_env(|name| std::env::var(name).ok())?
+        {
+            opts.connection_string = connection_string;
+        }
+    }
+    if let Some(path) = matches.get_one::<String>("password-file") {
+        if opts.connection_string.is_empty() {
+            return Err("Password file given without a connection string".to_string());
+        }
+        let password = credentials::password_from_file(path)?;
+        opts.connection_string =
+            credentials::with_password(&opts.mode, &opts.connection_string, &password)?;
+    }
+    Ok(())
+}
+
+// Logging isn't set up until we've got the options, so this goes straight to stderr
+fn usage_error(message: &str) -> ! {
+    eprintln!("error: {}", credentials::redact(message));
+    std::process::exit(exitcode::USAGE);
 }
 
-#[cfg(test)]
 impl Opts {
+    /// Named options for each database to wait for, starting with `-m/-c` if they were given
+    pub fn split_targets(&self) -> Vec<(String, Opts)> {
+        let mut targets = Vec::new();
+        if !self.connection_string.is_empty() {
+            targets.push(Target {
+                name: None,
+                mode: self.mode.clone(),
+                connection_string: self.connection_string.clone(),
+                sql_query: self.sql_query.clone(),
+            });
+        }
+        targets.extend(self.targets.iter().cloned());
+
+        let mut split: Vec<(String, Opts)> = Vec::new();
+        for (index, target) in targets.into_iter().enumerate() {
+            let mut name = target
+                .name
+                .clone()
+                .unwrap_or_else(|| target.mode.name().to_string());
+            if split.iter().any(|(existing, _)| *existing == name) {
+                name = format!("{}-{}", name, index + 1);
+            }
+            let mut opts = self.clone();
+            opts.mode = target.mode;
+            opts.connection_string = target.connection_string;
+            opts.sql_query = target.sql_query;
+            opts.targets = Vec::new();
+            split.push((name, opts));
+        }
+        split
+    }
+}
+
+impl Default for Opts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Opts {
+    /// Defaults as for the command line, with no connection string
     pub fn new() -> Self {
         Opts {
-            mode: DbMode::Odbc,
+            mode: DbMode::default(),
             connection_string: "".to_string(),
             sql_query: None,
-            timeout_seconds: None,
+            timeout: None,
+            connect_timeout: None,
+            query_timeout: None,
             quiet: false,
-            pause_seconds: 3,
+            verbose: 0,
+            pause: Duration::from_secs(3),
+            backoff: Backoff::Fixed,
+            max_pause: None,
+            ca_file: None,
+            client_cert: None,
+            client_key: None,
+            min_rows: 1,
+            max_rows: None,
+            expectations: Vec::new(),
+            command: Vec::new(),
+            strict: true,
+            output: OutputFormat::Text,
+            result_format: None,
+            targets: Vec::new(),
+            any: false,
         }
     }
+}
 
+#[cfg(test)]
+impl Opts {
     pub fn connection_string<I>(mut self, cs: I) -> Self
     where
         I: Into<String>,
@@ -117,9 +641,25 @@ impl Opts {
         self.sql_query = Some(st.into());
         self
     }
+
+    pub fn ca_file<I>(mut self, path: I) -> Self
+    where
+        I: Into<String>,
+    {
+        self.ca_file = Some(path.into());
+        self
+    }
+
+    pub fn client_cert<I>(mut self, path: I) -> Self
+    where
+        I: Into<String>,
+    {
+        self.client_cert = Some(path.into());
+        self
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum DbErrorLifetime {
     Permanent,
     Temporary,
@@ -127,9 +667,41 @@ pub enum DbErrorLifetime {
 
 #[derive(Debug)]
 pub enum DbErrorType {
-    OdbcError { error: Record },
-    PostgresError { error: Box<dyn std::error::Error> },
-    UrlError { error: ParseError },
+    #[cfg(feature = "odbc")]
+    OdbcError {
+        error: Record,
+    },
+    #[cfg(feature = "postgres")]
+    PostgresError {
+        error: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[cfg(feature = "mysql")]
+    MysqlError {
+        error: mysql::Error,
+    },
+    #[cfg(feature = "mssql")]
+    MssqlError {
+        error: tiberius::error::Error,
+    },
+    #[cfg(feature = "sqlite")]
+    SqliteError {
+        error: rusqlite::Error,
+    },
+    #[cfg(feature = "postgres")]
+    TlsError {
+        error: Box<dyn std::error::Error + Send + Sync>,
+    },
+    UrlError {
+        error: ParseError,
+    },
+    RowCountError {
+        rows: usize,
+        min_rows: usize,
+        max_rows: Option<usize>,
+    },
+    ExpectationError {
+        description: String,
+    },
 }
 
 #[derive(Debug)]
@@ -137,3 +709,343 @@ pub struct DbError {
     pub kind: DbErrorLifetime,
     pub error: DbErrorType,
 }
+
+/// Broad reasons for giving up, each of which gets its own exit code
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FailureCategory {
+    Timeout,
+    Auth,
+    Config,
+    Query,
+    DriverMissing,
+    Other,
+}
+
+impl FailureCategory {
+    pub fn exit_code(self) -> exitcode::ExitCode {
+        match self {
+            FailureCategory::Timeout => exitcode::UNAVAILABLE,
+            FailureCategory::Auth => exitcode::NOPERM,
+            FailureCategory::Config => exitcode::CONFIG,
+            FailureCategory::Query => exitcode::DATAERR,
+            FailureCategory::DriverMissing => exitcode::OSFILE,
+            FailureCategory::Other => exitcode::SOFTWARE,
+        }
+    }
+}
+
+impl DbError {
+    /// Whether it's worth trying again
+    pub fn lifetime(&self) -> DbErrorLifetime {
+        self.kind
+    }
+
+    /// SQLSTATE from the database or driver, if there is one
+    pub fn sqlstate(&self) -> Option<String> {
+        crate::output::error_details(&self.error).0
+    }
+
+    /// Driver-specific error number e.g. MySQL's 1045, if there is one
+    pub fn native_code(&self) -> Option<i64> {
+        crate::output::error_details(&self.error).1
+    }
+
+    /// What went wrong, without the SQLSTATE or native code
+    pub fn message(&self) -> String {
+        crate::output::error_details(&self.error).2
+    }
+
+    pub fn category(&self) -> FailureCategory {
+        // Temporary errors only stop us when we run out of time
+        if self.kind == DbErrorLifetime::Temporary {
+            return FailureCategory::Timeout;
+        }
+        match &self.error {
+            #[cfg(feature = "odbc")]
+            DbErrorType::OdbcError { .. } => match self.sqlstate().unwrap_or_default().as_str() {
+                "IM002" | "01000" | "IM004" => FailureCategory::DriverMissing,
+                c if c.starts_with("28") => FailureCategory::Auth,
+                c if c.starts_with("42") => FailureCategory::Query,
+                _ => FailureCategory::Other,
+            },
+            #[cfg(feature = "postgres")]
+            DbErrorType::PostgresError { .. } => match self.sqlstate().unwrap_or_default().as_str()
+            {
+                c if c.starts_with("28") => FailureCategory::Auth,
+                c if c.starts_with("42") => FailureCategory::Query,
+                // No SQLSTATE means it was a bad connection string or certificate
+                "" => FailureCategory::Config,
+                _ => FailureCategory::Other,
+            },
+            #[cfg(feature = "mysql")]
+            DbErrorType::MysqlError { error } => match (error, self.native_code()) {
+                (_, Some(1044)) | (_, Some(1045)) => FailureCategory::Auth,
+                (_, Some(1064)) => FailureCategory::Query,
+                (mysql::Error::UrlError(_), _) => FailureCategory::Config,
+                _ => FailureCategory::Other,
+            },
+            #[cfg(feature = "mssql")]
+            DbErrorType::MssqlError { .. } => match self.native_code() {
+                Some(18456) => FailureCategory::Auth,
+                Some(102) | Some(156) => FailureCategory::Query,
+                // Nothing from the server means a bad connection string or certificate
+                None => FailureCategory::Config,
+                _ => FailureCategory::Other,
+            },
+            #[cfg(feature = "sqlite")]
+            DbErrorType::SqliteError { error } => match crate::sqlite::error_code(error) {
+                Some(rusqlite::ErrorCode::NotADatabase)
+                | Some(rusqlite::ErrorCode::DatabaseCorrupt)
+                | None => FailureCategory::Config,
+                Some(rusqlite::ErrorCode::Unknown) | Some(rusqlite::ErrorCode::ReadOnly) => {
+                    FailureCategory::Query
+                }
+                _ => FailureCategory::Other,
+            },
+            #[cfg(feature = "postgres")]
+            DbErrorType::TlsError { .. } => FailureCategory::Config,
+            DbErrorType::UrlError { .. } => FailureCategory::Config,
+            DbErrorType::RowCountError { .. } | DbErrorType::ExpectationError { .. } => {
+                FailureCategory::Other
+            }
+        }
+    }
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (code, native_code, message) = crate::output::error_details(&self.error);
+        write!(f, "{}", credentials::redact(&message))?;
+        match (code, native_code) {
+            (Some(code), Some(native_code)) => {
+                write!(f, " (SQLSTATE {}, native error {})", code, native_code)
+            }
+            (Some(code), None) => write!(f, " (SQLSTATE {})", code),
+            (None, Some(native_code)) => write!(f, " (native error {})", native_code),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for DbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.error {
+            #[cfg(feature = "postgres")]
+            DbErrorType::PostgresError { error } | DbErrorType::TlsError { error } => {
+                Some(error.as_ref())
+            }
+            #[cfg(feature = "mysql")]
+            DbErrorType::MysqlError { error } => Some(error),
+            #[cfg(feature = "mssql")]
+            DbErrorType::MssqlError { error } => Some(error),
+            #[cfg(feature = "sqlite")]
+            DbErrorType::SqliteError { error } => Some(error),
+            DbErrorType::UrlError { error } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Exit code for giving up because of this error
+pub fn exit_code(error: &DbError) -> exitcode::ExitCode {
+    error.category().exit_code()
+}
+
+pub fn check_results(opts: &Opts, results: &[Row]) -> Result<(), DbError> {
+    if opts.sql_query.is_none() {
+        return Ok(());
+    }
+    let rows = results.len();
+    if rows < opts.min_rows || opts.max_rows.is_some_and(|max_rows| rows > max_rows) {
+        return Err(DbError {
+            kind: DbErrorLifetime::Temporary,
+            error: DbErrorType::RowCountError {
+                rows,
+                min_rows: opts.min_rows,
+                max_rows: opts.max_rows,
+            },
+        });
+    }
+    expect::check(&opts.expectations, results)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rows(count: usize) -> Vec<Row> {
+        (0..count)
+            .map(|i| std::iter::once(("id", crate::value::Value::Int(i as i64))).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("3"), Ok(Duration::from_secs(3)));
+        assert_eq!(parse_duration("0.5"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("1.5s"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("1m 30s"), Ok(Duration::from_secs(90)));
+        assert!(parse_duration("-1").is_err());
+        assert!(parse_duration("soon").is_err());
+    }
+
+    fn permanent(error: DbErrorType) -> DbError {
+        DbError {
+            kind: DbErrorLifetime::Permanent,
+            error,
+        }
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        let url_error = permanent(DbErrorType::UrlError {
+            error: ParseError::InvalidPort,
+        });
+        assert_eq!(url_error.category(), FailureCategory::Config);
+        assert_eq!(exit_code(&url_error), exitcode::CONFIG);
+
+        #[cfg(feature = "mysql")]
+        {
+            let mysql_error = |code| {
+                permanent(DbErrorType::MysqlError {
+                    error: mysql::Error::MySqlError(mysql::MySqlError {
+                        state: "HY000".to_string(),
+                        message: "test".to_string(),
+                        code,
+                    }),
+                })
+            };
+            assert_eq!(exit_code(&mysql_error(1045)), exitcode::NOPERM);
+            assert_eq!(exit_code(&mysql_error(1064)), exitcode::DATAERR);
+        }
+
+        let temporary = DbError {
+            kind: DbErrorLifetime::Temporary,
+            error: DbErrorType::RowCountError {
+                rows: 0,
+                min_rows: 1,
+                max_rows: None,
+            },
+        };
+        assert_eq!(exit_code(&temporary), exitcode::UNAVAILABLE);
+    }
+
+    #[test]
+    fn test_error_display() {
+        let url_error = permanent(DbErrorType::UrlError {
+            error: ParseError::InvalidPort,
+        });
+        assert_eq!(url_error.to_string(), "invalid port number");
+        assert_eq!(url_error.lifetime(), DbErrorLifetime::Permanent);
+        assert_eq!(url_error.sqlstate(), None);
+        assert!(std::error::Error::source(&url_error).is_some());
+
+        let boxed: Box<dyn std::error::Error> = Box::new(permanent(DbErrorType::RowCountError {
+            rows: 0,
+            min_rows: 1,
+            max_rows: None,
+        }));
+        assert_eq!(
+            boxed.to_string(),
+            "Query returned 0 rows, expected at least 1"
+        );
+        assert!(boxed.source().is_none());
+
+        #[cfg(feature = "mysql")]
+        {
+            let mysql_error = permanent(DbErrorType::MysqlError {
+                error: mysql::Error::MySqlError(mysql::MySqlError {
+                    state: "28000".to_string(),
+                    message: "Access denied for user 'app'".to_string(),
+                    code: 1045,
+                }),
+            });
+            assert_eq!(
+                mysql_error.to_string(),
+                "Access denied for user 'app' (SQLSTATE 28000, native error 1045)"
+            );
+            assert_eq!(mysql_error.sqlstate().as_deref(), Some("28000"));
+            assert_eq!(mysql_error.native_code(), Some(1045));
+            assert_eq!(mysql_error.message(), "Access denied for user 'app'");
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "odbc", feature = "postgres", feature = "mysql"))]
+    fn test_parse_target() {
+        let target: Target = "mode=odbc,url=Driver=foo;Server=a,b;,query=SELECT a, b FROM c"
+            .parse()
+            .unwrap();
+        assert!(target.mode == DbMode::Odbc);
+        assert_eq!(target.connection_string, "Driver=foo;Server=a,b;");
+        assert_eq!(target.sql_query.as_deref(), Some("SELECT a, b FROM c"));
+        assert_eq!(target.name, None);
+
+        let target: Target = "name=reports,mode=mysql,url=mysql://localhost"
+            .parse()
+            .unwrap();
+        assert_eq!(target.name.as_deref(), Some("reports"));
+        assert_eq!(target.sql_query, None);
+
+        assert!("url=postgresql://localhost".parse::<Target>().is_err());
+        assert!("mode=postgres".parse::<Target>().is_err());
+        assert!("mode=oracle,url=foo".parse::<Target>().is_err());
+        assert!("timeout=3,mode=postgres,url=foo".parse::<Target>().is_err());
+        assert!("mode=postgres,url=foo,mode=odbc".parse::<Target>().is_err());
+    }
+
+    #[test]
+    #[cfg(all(feature = "odbc", feature = "postgres"))]
+    fn test_split_targets() {
+        let mut opts = Opts::new()
+            .connection_string("Driver=foo;")
+            .sql_query("SELECT 1");
+        assert_eq!(opts.split_targets().len(), 1);
+
+        opts.targets = vec![
+            "mode=odbc,url=Driver=bar;".parse().unwrap(),
+            "name=main,mode=postgres,url=postgresql://localhost"
+                .parse()
+                .unwrap(),
+        ];
+        let targets = opts.split_targets();
+        let names: Vec<&str> = targets.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["odbc", "odbc-2", "main"]);
+        assert_eq!(targets[1].1.connection_string, "Driver=bar;");
+        assert_eq!(targets[1].1.sql_query, None);
+        assert!(targets[2].1.mode == DbMode::Postgres);
+    }
+
+    #[test]
+    fn test_check_results_without_query() {
+        check_results(&Opts::new(), &rows(0)).unwrap();
+    }
+
+    #[test]
+    fn test_check_results_with_no_rows() {
+        let err = check_results(&Opts::new().sql_query("SELECT 1"), &rows(0)).unwrap_err();
+        assert_eq!(err.kind, DbErrorLifetime::Temporary, "{:?}", err);
+        assert!(
+            matches!(err.error, DbErrorType::RowCountError { rows: 0, .. }),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_check_results_with_row_limits() {
+        let mut opts = Opts::new().sql_query("SELECT 1");
+        opts.min_rows = 2;
+        opts.max_rows = Some(3);
+        check_results(&opts, &rows(1)).unwrap_err();
+        check_results(&opts, &rows(2)).unwrap();
+        check_results(&opts, &rows(3)).unwrap();
+        check_results(&opts, &rows(4)).unwrap_err();
+
+        opts.min_rows = 0;
+        check_results(&opts, &rows(0)).unwrap();
+    }
+}
@@ -0,0 +1,296 @@
+use crate::common::{parse_duration, DbMode, Opts, Target};
+use crate::expect::Expectation;
+use crate::retry::Backoff;
+use serde::Deserialize;
+use std::{fs, path::Path, time::Duration};
+
+/// Durations can be plain numbers of seconds, or strings like on the command line e.g. "250ms"
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum DurationValue {
+    Seconds(f64),
+    Text(String),
+}
+
+impl DurationValue {
+    fn parse(&self, key: &str) -> Result<Duration, String> {
+        match self {
+            DurationValue::Seconds(seconds) => {
+                Duration::try_from_secs_f64(*seconds).map_err(|e| e.to_string())
+            }
+            DurationValue::Text(text) => parse_duration(text),
+        }
+        .map_err(|e| format!("Bad {}: {}", key, e))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TargetConfig {
+    name: Option<String>,
+    mode: String,
+    url: String,
+    query: Option<String>,
+}
+
+/// Contents of a `--config` file, with the same names as the command line options
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    mode: Option<String>,
+    connection_string: Option<String>,
+    sql_query: Option<String>,
+    #[serde(default)]
+    expect: Vec<String>,
+    min_rows: Option<usize>,
+    max_rows: Option<usize>,
+    timeout: Option<DurationValue>,
+    connect_timeout: Option<DurationValue>,
+    query_timeout: Option<DurationValue>,
+    pause: Option<DurationValue>,
+    backoff: Option<String>,
+    max_pause: Option<DurationValue>,
+    ca_file: Option<String>,
+    client_cert: Option<String>,
+    client_key: Option<String>,
+    #[serde(default)]
+    targets: Vec<TargetConfig>,
+    any: Option<bool>,
+}
+
+fn parse_mode(mode: &str) -> Result<DbMode, String> {
+    if DbMode::names().contains(&mode) {
+        Ok(DbMode::from_str(mode))
+    } else {
+        Err(format!(
+            "Unknown mode '{}' (expected one of {})",
+            mode,
+            DbMode::names().join(", ")
+        ))
+    }
+}
+
+impl Config {
+    /// TOML, or YAML for files ending in `.yaml`/`.yml`
+    pub fn load(path: &str) -> Result<Config, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        match extension.as_str() {
+            "yaml" | "yml" => Config::from_yaml(&contents),
+            _ => Config::from_toml(&contents),
+        }
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Config, String> {
+        toml::from_str(contents).map_err(|e| e.message().to_string())
+    }
+
+    pub fn from_yaml(contents: &str) -> Result<Config, String> {
+        serde_yaml::from_str(contents).map_err(|e| e.to_string())
+    }
+
+    pub fn has_mode(&self) -> bool {
+        self.mode.is_some()
+    }
+
+    /// Fills in the options that weren't given on the command line
+    pub fn apply<F>(&self, opts: &mut Opts, from_command_line: F) -> Result<(), String>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let unset = |id: &str| !from_command_line(id);
+        let duration = |value: &Option<DurationValue>, key: &str| -> Result<_, String> {
+            value.as_ref().map(|v| v.parse(key)).transpose()
+        };
+
+        if let (Some(mode), true) = (&self.mode, unset("mode")) {
+            opts.mode = parse_mode(mode)?;
+        }
+        if let (Some(connection_string), true) =
+            (&self.connection_string, unset("connection-string"))
+        {
+            opts.connection_string = connection_string.clone();
+        }
+        if let (Some(sql_query), true) = (&self.sql_query, unset("sql-query")) {
+            opts.sql_query = Some(sql_query.clone());
+        }
+        if !self.expect.is_empty() && unset("expect") {
+            opts.expectations = self
+                .expect
+                .iter()
+                .map(|e| e.parse::<Expectation>())
+                .collect::<Result<_, _>>()?;
+        }
+        if let (Some(min_rows), true) = (self.min_rows, unset("min-rows")) {
+            opts.min_rows = min_rows;
+        }
+        if let (Some(max_rows), true) = (self.max_rows, unset("max-rows")) {
+            opts.max_rows = Some(max_rows);
+        }
+        if let (Some(timeout), true) = (duration(&self.timeout, "timeout")?, unset("timeout")) {
+            opts.timeout = Some(timeout);
+        }
+        if let (Some(timeout), true) = (
+            duration(&self.connect_timeout, "connect-timeout")?,
+            unset("connect-timeout"),
+        ) {
+            opts.connect_timeout = Some(timeout);
+        }
+        if let (Some(timeout), true) = (
+            duration(&self.query_timeout, "query-timeout")?,
+            unset("query-timeout"),
+        ) {
+            opts.query_timeout = Some(timeout);
+        }
+        if let (Some(pause), true) = (duration(&self.pause, "pause")?, unset("pause")) {
+            opts.pause = pause;
+        }
+        if let (Some(backoff), true) = (&self.backoff, unset("backoff")) {
+            if !["fixed", "linear", "exponential", "decorrelated-jitter"]
+                .contains(&backoff.as_str())
+            {
+                return Err(format!("Unknown backoff '{}'", backoff));
+            }
+            opts.backoff = Backoff::from_str(backoff);
+        }
+        if let (Some(max_pause), true) =
+            (duration(&self.max_pause, "max-pause")?, unset("max-pause"))
+        {
+            opts.max_pause = Some(max_pause);
+        }
+        if let (Some(ca_file), true) = (&self.ca_file, unset("ca-file")) {
+            opts.ca_file = Some(ca_file.clone());
+        }
+        // Certificate and key only make sense as a pair, so take both from the same place
+        if unset("client-cert") && unset("client-key") {
+            match (&self.client_cert, &self.client_key) {
+                (Some(cert), Some(key)) => {
+                    opts.client_cert = Some(cert.clone());
+                    opts.client_key = Some(key.clone());
+                }
+                (None, None) => {}
+                _ => return Err("client-cert and client-key must be given together".to_string()),
+            }
+        }
+        if !self.targets.is_empty() && unset("target") {
+            opts.targets = self
+                .targets
+                .iter()
+                .map(|t| {
+                    if t.url.is_empty() {
+                        return Err("Target with an empty url".to_string());
+                    }
+                    Ok(Target {
+                        name: t.name.clone(),
+                        mode: parse_mode(&t.mode)?,
+                        connection_string: t.url.clone(),
+                        sql_query: t.query.clone().filter(|q| !q.is_empty()),
+                    })
+                })
+                .collect::<Result<_, _>>()?;
+        }
+        if let (Some(any), true) = (self.any, unset("any")) {
+            opts.any = any;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "odbc")]
+    fn test_toml_config() {
+        let config = Config::from_toml(
+            r#"
+mode = "odbc"
+connection-string = "Driver=foo;"
+sql-query = "SELECT status FROM migrations"
+expect = ["status=done"]
+timeout = "2m"
+pause = 0.5
+"#,
+        )
+        .unwrap();
+        let mut opts = Opts::new();
+        config.apply(&mut opts, |_| false).unwrap();
+        assert!(opts.mode == DbMode::Odbc);
+        assert_eq!(opts.connection_string, "Driver=foo;");
+        assert_eq!(
+            opts.sql_query.as_deref(),
+            Some("SELECT status FROM migrations")
+        );
+        assert_eq!(opts.expectations.len(), 1);
+        assert_eq!(opts.timeout, Some(Duration::from_secs(120)));
+        assert_eq!(opts.pause, Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_command_line_overrides_config() {
+        let config = Config::from_toml("connection-string = \"Driver=foo;\"\npause = 10").unwrap();
+        let mut opts = Opts::new().connection_string("Driver=bar;");
+        config
+            .apply(&mut opts, |id| id == "connection-string")
+            .unwrap();
+        assert_eq!(opts.connection_string, "Driver=bar;");
+        assert_eq!(opts.pause, Duration::from_secs(10));
+    }
+
+    #[test]
+    #[cfg(all(feature = "odbc", feature = "postgres"))]
+    fn test_yaml_config_with_targets() {
+        let config = Config::from_yaml(
+            r#"
+targets:
+  - name: main
+    mode: postgres
+    url: postgresql://localhost
+  - mode: odbc
+    url: Driver=foo;
+    query: SELECT 1
+any: true
+"#,
+        )
+        .unwrap();
+        let mut opts = Opts::new();
+        config.apply(&mut opts, |_| false).unwrap();
+        assert_eq!(opts.targets.len(), 2);
+        assert_eq!(opts.targets[0].name.as_deref(), Some("main"));
+        assert_eq!(opts.targets[1].sql_query.as_deref(), Some("SELECT 1"));
+        assert!(opts.any);
+    }
+
+    #[test]
+    #[cfg(all(
+        feature = "odbc",
+        feature = "postgres",
+        feature = "mysql",
+        feature = "mssql",
+        feature = "sqlite"
+    ))]
+    fn test_invalid_config() {
+        assert!(Config::from_toml("colour = \"blue\"").is_err());
+        assert!(Config::from_toml("pause = [1]").is_err());
+
+        let invalid = |contents: &str| {
+            Config::from_toml(contents)
+                .unwrap()
+                .apply(&mut Opts::new(), |_| false)
+                .unwrap_err()
+        };
+        assert_eq!(
+            invalid("mode = \"oracle\""),
+            "Unknown mode 'oracle' (expected one of odbc, postgres, mysql, mssql, sqlite)"
+        );
+        assert!(invalid("timeout = \"soon\"").starts_with("Bad timeout"));
+        assert!(invalid("pause = -1").starts_with("Bad pause"));
+        assert!(invalid("expect = [\"status\"]").contains("No comparison"));
+        assert!(invalid("client-cert = \"cert.pem\"").contains("together"));
+    }
+}
@@ -0,0 +1,254 @@
+use crate::common::{DbError, DbErrorLifetime, DbErrorType};
+use crate::value::{normalize_decimal, Row, Value};
+use regex::Regex;
+use std::{fmt, str::FromStr};
+
+#[derive(Clone, Debug)]
+pub enum Comparison {
+    Equal(String),
+    NotEqual(String),
+    Matches(Regex),
+    LessThan(f64),
+    LessOrEqual(f64),
+    GreaterThan(f64),
+    GreaterOrEqual(f64),
+}
+
+/// A predicate on a single column of the query results e.g. `status=done`
+#[derive(Clone, Debug)]
+pub struct Expectation {
+    pub column: String,
+    pub comparison: Comparison,
+}
+
+impl FromStr for Expectation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let index = s
+            .find(['=', '!', '~', '<', '>'])
+            .ok_or_else(|| format!("No comparison found in '{}'", s))?;
+        let column = s[..index].trim().to_string();
+        if column.is_empty() {
+            return Err(format!("No column name found in '{}'", s));
+        }
+        let rest = &s[index..];
+        let (op, value) = ["!=", ">=", "<=", "=", "~", ">", "<"]
+            .iter()
+            .find_map(|op| rest.strip_prefix(op).map(|value| (*op, value)))
+            .ok_or_else(|| format!("Unknown comparison in '{}'", s))?;
+        let number = || {
+            value
+                .trim()
+                .parse::<f64>()
+                .map_err(|_| format!("'{}' isn't a number in '{}'", value, s))
+        };
+        let comparison = match op {
+            "=" => Comparison::Equal(value.trim().to_string()),
+            "!=" => Comparison::NotEqual(value.trim().to_string()),
+            "~" => Comparison::Matches(Regex::new(value).map_err(|e| e.to_string())?),
+            "<" => Comparison::LessThan(number()?),
+            "<=" => Comparison::LessOrEqual(number()?),
+            ">" => Comparison::GreaterThan(number()?),
+            ">=" => Comparison::GreaterOrEqual(number()?),
+            _ => unreachable!(),
+        };
+        Ok(Expectation { column, comparison })
+    }
+}
+
+impl fmt::Display for Expectation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.comparison {
+            Comparison::Equal(value) => write!(f, "{} = '{}'", self.column, value),
+            Comparison::NotEqual(value) => write!(f, "{} != '{}'", self.column, value),
+            Comparison::Matches(regex) => write!(f, "{} ~ /{}/", self.column, regex),
+            Comparison::LessThan(value) => write!(f, "{} < {}", self.column, value),
+            Comparison::LessOrEqual(value) => write!(f, "{} <= {}", self.column, value),
+            Comparison::GreaterThan(value) => write!(f, "{} > {}", self.column, value),
+            Comparison::GreaterOrEqual(value) => write!(f, "{} >= {}", self.column, value),
+        }
+    }
+}
+
+impl Expectation {
+    /// NULLs don't match anything, as in SQL
+    pub fn matches(&self, row: &Row) -> bool {
+        let value = match row.get(&self.column) {
+            Some(Value::Null) | None => return false,
+            Some(value) => value,
+        };
+        let text = value.to_string();
+        let number = || value.as_f64();
+        // Numeric columns compare as numbers, so `price=12.50` matches 12.5
+        let equal = |expected: &String| match value {
+            Value::Int(_) | Value::Float(_) => {
+                text == *expected || expected.trim().parse::<f64>().ok() == number()
+            }
+            // Exactly, as 12345678901234567.89 and 12345678901234567.9 are the same float
+            Value::Decimal(_) => match normalize_decimal(expected) {
+                Some(expected) => normalize_decimal(&text) == Some(expected),
+                None => text == *expected || expected.trim().parse::<f64>().ok() == number(),
+            },
+            _ => text == *expected,
+        };
+        match &self.comparison {
+            Comparison::Equal(expected) => equal(expected),
+            Comparison::NotEqual(expected) => !equal(expected),
+            Comparison::Matches(regex) => regex.is_match(&text),
+            Comparison::LessThan(expected) => number().is_some_and(|n| n < *expected),
+            Comparison::LessOrEqual(expected) => number().is_some_and(|n| n <= *expected),
+            Comparison::GreaterThan(expected) => number().is_some_and(|n| n > *expected),
+            Comparison::GreaterOrEqual(expected) => number().is_some_and(|n| n >= *expected),
+        }
+    }
+}
+
+/// Succeeds if at least one row matches all of the expectations
+pub fn check(expectations: &[Expectation], results: &[Row]) -> Result<(), DbError> {
+    if expectations.is_empty()
+        || results
+            .iter()
+            .any(|row| expectations.iter().all(|e| e.matches(row)))
+    {
+        return Ok(());
+    }
+
+    let description = match results.first() {
+        None => "no rows returned".to_string(),
+        Some(row) => expectations
+            .iter()
+            .filter(|e| !e.matches(row))
+            .map(|e| match row.get(&e.column) {
+                Some(Value::Null) => format!("expected {} but got NULL", e),
+                Some(value) => format!("expected {} but got '{}'", e, value),
+                None => format!("expected {} but there's no such column", e),
+            })
+            .collect::<Vec<String>>()
+            .join(", "),
+    };
+    Err(DbError {
+        kind: DbErrorLifetime::Temporary,
+        error: DbErrorType::ExpectationError { description },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn row(values: &[(&str, &str)]) -> Row {
+        values
+            .iter()
+            .map(|(k, v)| (k.to_string(), Value::Text(v.to_string())))
+            .collect()
+    }
+
+    fn expect(s: &str) -> Expectation {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_expectations() {
+        assert!(matches!(
+            expect("status=done").comparison,
+            Comparison::Equal(ref v) if v == "done"
+        ));
+        assert!(matches!(
+            expect("status = done").comparison,
+            Comparison::Equal(ref v) if v == "done"
+        ));
+        assert!(matches!(
+            expect("status!=").comparison,
+            Comparison::NotEqual(ref v) if v.is_empty()
+        ));
+        assert!(matches!(
+            expect("count >= 3").comparison,
+            Comparison::GreaterOrEqual(v) if v == 3.0
+        ));
+        assert_eq!(expect("version~^1\\.").column, "version");
+        assert!("status".parse::<Expectation>().is_err());
+        assert!("=done".parse::<Expectation>().is_err());
+        assert!("count>many".parse::<Expectation>().is_err());
+        assert!("version~(".parse::<Expectation>().is_err());
+    }
+
+    #[test]
+    fn test_expectation_matches() {
+        let r = row(&[("status", "done"), ("count", "12")]);
+        assert!(expect("status=done").matches(&r));
+        assert!(!expect("status=running").matches(&r));
+        assert!(expect("status!=running").matches(&r));
+        assert!(expect("status~^d").matches(&r));
+        assert!(expect("count>11.5").matches(&r));
+        assert!(expect("count<=12").matches(&r));
+        assert!(!expect("count<12").matches(&r));
+        assert!(!expect("status>1").matches(&r));
+        assert!(!expect("missing=done").matches(&r));
+    }
+
+    #[test]
+    fn test_typed_values() {
+        let r: Row = vec![
+            ("count", Value::Int(3)),
+            ("price", Value::Float(12.5)),
+            ("total", Value::Decimal("12345678901234567.80".to_string())),
+            ("ready", Value::Bool(true)),
+            ("finished", Value::Null),
+            ("notes", Value::Text("".to_string())),
+        ]
+        .into_iter()
+        .collect();
+        assert!(expect("count>=3").matches(&r));
+        assert!(expect("count=3").matches(&r));
+        assert!(expect("price=12.50").matches(&r));
+        assert!(expect("total=12345678901234567.8").matches(&r));
+        assert!(!expect("total=12345678901234567.81").matches(&r));
+        assert!(expect("total!=12345678901234567.81").matches(&r));
+        assert!(expect("total>1e16").matches(&r));
+        assert!(expect("ready=true").matches(&r));
+        assert!(expect("notes=").matches(&r));
+        // NULL isn't the same as an empty string
+        assert!(!expect("finished=").matches(&r));
+        assert!(!expect("finished!=").matches(&r));
+
+        let err = check(&[expect("finished~.")], &[r]).unwrap_err();
+        match err.error {
+            DbErrorType::ExpectationError { description } => {
+                assert_eq!(description, "expected finished ~ /./ but got NULL")
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_check_expectations() {
+        let expectations = vec![expect("status=done"), expect("count>=10")];
+        check(
+            &expectations,
+            &[row(&[("status", "done"), ("count", "12")])],
+        )
+        .unwrap();
+        check(
+            &expectations,
+            &[
+                row(&[("status", "running"), ("count", "12")]),
+                row(&[("status", "done"), ("count", "10")]),
+            ],
+        )
+        .unwrap();
+
+        let err = check(
+            &expectations,
+            &[row(&[("status", "running"), ("count", "12")])],
+        )
+        .unwrap_err();
+        assert_eq!(err.kind, DbErrorLifetime::Temporary);
+        match err.error {
+            DbErrorType::ExpectationError { description } => {
+                assert_eq!(description, "expected status = 'done' but got 'running'")
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+}
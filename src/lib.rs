@@ -1,3 +1,27 @@
+#[cfg(feature = "async")]
+pub mod asynchronous;
 pub mod common;
+pub mod config;
+pub mod credentials;
+pub mod expect;
+#[cfg(feature = "mssql")]
+pub mod mssql;
+#[cfg(feature = "mysql")]
+pub mod mysql;
+#[cfg(feature = "odbc")]
 pub mod odbc;
+pub mod output;
+#[cfg(feature = "postgres")]
 pub mod pg;
+pub mod retry;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod value;
+pub mod waiter;
+
+pub use common::{DbMode, Target};
+pub use expect::Expectation;
+pub use output::OutputFormat;
+pub use retry::Backoff;
+pub use value::{Column, Row, Value};
+pub use waiter::{TargetOutcome, WaitError, WaitOutcome, Waiter};
@@ -1,70 +1,114 @@
 use env_logger::Builder;
+use log::{error, LevelFilter};
 use std::env;
-use std::thread;
-use std::time::{Duration, Instant};
+use std::io::Write;
+use std::process::Command;
 
 use wait_for_db::common;
-use wait_for_db::odbc;
-use wait_for_db::pg;
+use wait_for_db::credentials;
+use wait_for_db::output;
+use wait_for_db::{Row, WaitError, WaitOutcome, Waiter};
 
-fn main() {
-    let mut opt = common::parse_args();
-    Builder::new()
-        .parse_filters(&env::var("WAIT_DB_LOG").unwrap_or_else(|_| "odbc=off".to_string()))
-        .init();
+#[cfg(unix)]
+fn run_command(command: &[String]) -> ! {
+    use std::os::unix::process::CommandExt;
+    // exec only returns if it failed to replace us
+    let error = Command::new(&command[0]).args(&command[1..]).exec();
+    error!("Failed to run {}: {}", command[0], error);
+    std::process::exit(127);
+}
 
-    if opt.pause_seconds == 0 {
-        println!("Pause between checks should be at least 1 second");
-        std::process::exit(exitcode::USAGE);
+#[cfg(not(unix))]
+fn run_command(command: &[String]) -> ! {
+    match Command::new(&command[0]).args(&command[1..]).status() {
+        Ok(status) => std::process::exit(status.code().unwrap_or(exitcode::SOFTWARE)),
+        Err(error) => {
+            error!("Failed to run {}: {}", command[0], error);
+            std::process::exit(127);
+        }
     }
+}
 
-    if opt.mode == common::DbMode::Postgres {
-        pg::rewrite_connection_string(&mut opt).unwrap();
+/// Exits, or hands over to the wrapped command if there is one
+fn finish(opt: &common::Opts, code: exitcode::ExitCode) -> ! {
+    if !opt.command.is_empty() && (code == exitcode::OK || !opt.strict) {
+        run_command(&opt.command);
     }
+    std::process::exit(code);
+}
 
-    let timeout = opt.timeout_seconds.map(Duration::from_secs);
-    let start = Instant::now();
-    while timeout.is_none() || start.elapsed() < timeout.unwrap() {
-        match if opt.mode == common::DbMode::Odbc {
-            odbc::connect(&opt)
+fn init_logging(opt: &common::Opts) {
+    let level = match (opt.quiet, opt.verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    let mut builder = Builder::new();
+    builder
+        // Results printed with --result-format get stdout to themselves, e.g. for `$(...)`
+        .target(match opt.result_format {
+            Some(_) => env_logger::Target::Stderr,
+            None => env_logger::Target::Stdout,
+        })
+        .format(|buf, record| {
+            // Errors and driver diagnostics can include the connection string
+            let message = credentials::redact(&record.args().to_string());
+            if record.target().starts_with("wait_for_db") {
+                writeln!(buf, "{}", message)
+            } else {
+                writeln!(buf, "[{} {}] {}", record.level(), record.target(), message)
+            }
+        })
+        // Driver logging is only wanted for -vv, as it's very noisy
+        .filter_level(if opt.verbose >= 2 {
+            LevelFilter::Debug
         } else {
-            pg::connect(&opt)
-        } {
-            Ok(results) => {
-                if opt.sql_query.is_none() {
-                    println!("Success");
-                } else {
-                    println!("Success {:?}", results);
-                }
-                std::process::exit(exitcode::OK);
+            LevelFilter::Off
+        })
+        .filter_module("wait_for_db", level);
+    if let Ok(filters) = env::var("WAIT_DB_LOG") {
+        builder.parse_filters(&filters);
+    }
+    builder.init();
+}
+
+/// Prints the query results from all the ready targets, returning the exit code
+fn print_results(format: output::ResultFormat, outcome: &WaitOutcome) -> exitcode::ExitCode {
+    let rows: Vec<Row> = outcome
+        .targets
+        .iter()
+        .filter(|target| target.ready)
+        .flat_map(|target| target.rows.iter().cloned())
+        .collect();
+    match output::format_results(format, &rows) {
+        Ok(text) => match std::io::stdout().write_all(text.as_bytes()) {
+            Ok(()) => exitcode::OK,
+            Err(e) => {
+                error!("Failed to print results: {}", e);
+                exitcode::IOERR
             }
-            Err(dberror) => match dberror.kind {
-                common::DbErrorLifetime::Permanent => {
-                    println!("Permanent error: {:?}", dberror.error);
-                    std::process::exit(exitcode::UNAVAILABLE);
-                }
-                common::DbErrorLifetime::Temporary => {
-                    let pause_time = Duration::from_secs(opt.pause_seconds);
-                    if let Some(t) = timeout {
-                        let remaining = t - start.elapsed();
-                        if remaining < pause_time {
-                            println!(
-                                "Temporary error (exiting as out of time): {:?}",
-                                dberror.error
-                            );
-                            std::process::exit(exitcode::UNAVAILABLE);
-                        }
-                    }
-                    println!(
-                        "Temporary error (pausing for {} second{}): {:?}",
-                        opt.pause_seconds,
-                        if opt.pause_seconds == 1 { "" } else { "s" },
-                        dberror.error
-                    );
-                    thread::sleep(pause_time);
-                }
-            },
+        },
+        Err(message) => {
+            error!("{}", message);
+            exitcode::DATAERR
+        }
+    }
+}
+
+fn main() {
+    let opt = common::parse_args();
+    init_logging(&opt);
+
+    match Waiter::from_opts(opt.clone()).wait() {
+        Ok(outcome) => match opt.result_format {
+            Some(format) => finish(&opt, print_results(format, &outcome)),
+            None => finish(&opt, exitcode::OK),
+        },
+        Err(WaitError::InvalidOptions(message)) => {
+            error!("{}", message);
+            std::process::exit(exitcode::USAGE);
         }
+        Err(e) => finish(&opt, e.exit_code()),
     }
-    std::process::exit(exitcode::UNAVAILABLE);
 }
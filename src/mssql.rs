@@ -0,0 +1,566 @@
+use crate::common::{DbError, DbErrorLifetime, DbErrorType, Opts};
+use crate::credentials::odbc_attributes;
+use crate::value::{self, Column, Row, Value, MICROS_PER_DAY};
+use std::{future::Future, sync::Arc, time::Duration};
+use tiberius::{
+    error::{Error, IoErrorKind},
+    numeric::Numeric,
+    Client, ColumnData, ColumnType, Config, SqlBrowser,
+};
+use tokio::net::TcpStream;
+use tokio_util::compat::{Compat, TokioAsyncWriteCompatExt};
+
+impl From<Error> for DbError {
+    fn from(e: Error) -> Self {
+        let kind = match e {
+            Error::Server(ref error) => match error.code() {
+                18456 // login failed (bad user/password)
+                | 102 // syntax error
+                | 156 // syntax error near a keyword
+                => DbErrorLifetime::Permanent,
+                // including 4060 (can't open the database), as it may still be being created or recovered
+                _ => DbErrorLifetime::Temporary,
+            },
+            Error::Tls(_) => DbErrorLifetime::Permanent,
+            _ => DbErrorLifetime::Temporary,
+        };
+        DbError {
+            kind,
+            error: DbErrorType::MssqlError { error: e },
+        }
+    }
+}
+
+fn permanent(error: Error) -> DbError {
+    DbError {
+        kind: DbErrorLifetime::Permanent,
+        error: DbErrorType::MssqlError { error },
+    }
+}
+
+// ADO.NET connection strings, or JDBC ones starting with `jdbc:sqlserver://`
+fn config(opts: &Opts) -> Result<Config, DbError> {
+    let attributes = odbc_attributes(&opts.connection_string);
+    let has_key = |name: &str, values: &[&str]| {
+        attributes.iter().any(|(key, value)| {
+            key.trim().eq_ignore_ascii_case(name)
+                && (values.is_empty() || values.contains(&value.trim().to_lowercase().as_str()))
+        })
+    };
+    // tiberius panics if it's asked to both trust any certificate and check it against a CA
+    if has_key("TrustServerCertificate", &["true", "yes"])
+        && (opts.ca_file.is_some() || has_key("TrustServerCertificateCA", &[]))
+    {
+        return Err(permanent(Error::Conversion(
+            "TrustServerCertificate can't be used with a CA file".into(),
+        )));
+    }
+    let mut config = if opts.connection_string.starts_with("jdbc:") {
+        Config::from_jdbc_string(&opts.connection_string)
+    } else {
+        Config::from_ado_string(&opts.connection_string)
+    }
+    .map_err(permanent)?;
+    if let Some(ref ca_file) = opts.ca_file {
+        config.trust_cert_ca(ca_file);
+    }
+    Ok(config)
+}
+
+// Timeouts look like the driver's own I/O timeouts, so they're non-permanent failures
+async fn within<T, F>(limit: Option<Duration>, what: &str, future: F) -> Result<T, DbError>
+where
+    F: Future<Output = Result<T, DbError>>,
+{
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, future)
+            .await
+            .unwrap_or_else(|_| {
+                Err(Error::Io {
+                    kind: IoErrorKind::TimedOut,
+                    message: format!("{} timed out", what),
+                }
+                .into())
+            }),
+        None => future.await,
+    }
+}
+
+// Named instances (`server=tcp:host\instance`) need SQL Server Browser to find their port
+fn is_named_instance(connection_string: &str) -> bool {
+    match connection_string.strip_prefix("jdbc:") {
+        Some(jdbc) => jdbc.split(';').next().unwrap_or_default().contains('\\'),
+        None => odbc_attributes(connection_string)
+            .iter()
+            .any(|(key, value)| key.trim().eq_ignore_ascii_case("server") && value.contains('\\')),
+    }
+}
+
+async fn tcp_stream(config: &Config, named_instance: bool) -> Result<TcpStream, Error> {
+    // This hides why the connection failed, so is only used when it's needed
+    if named_instance {
+        return TcpStream::connect_named(config).await;
+    }
+    let tcp = TcpStream::connect(config.get_addr()).await?;
+    tcp.set_nodelay(true)?;
+    Ok(tcp)
+}
+
+async fn client(
+    config: Config,
+    named_instance: bool,
+) -> Result<Client<Compat<TcpStream>>, DbError> {
+    let tcp = tcp_stream(&config, named_instance).await?;
+    match Client::connect(config.clone(), tcp.compat_write()).await {
+        // Azure SQL can send us on to the server that actually has the database
+        Err(Error::Routing { host, port }) => {
+            let mut config = config;
+            config.host(host);
+            config.port(port);
+            let tcp = tcp_stream(&config, false).await?;
+            Ok(Client::connect(config, tcp.compat_write()).await?)
+        }
+        result => Ok(result?),
+    }
+}
+
+pub fn connect(opts: &Opts) -> std::result::Result<Vec<Row>, DbError> {
+    // The driver is async-only, so it gets a runtime of its own as the postgres one does
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(Error::from)?
+        .block_on(connect_async(opts))
+}
+
+/// As `connect`, but on the caller's tokio runtime rather than one of its own
+pub async fn connect_async(opts: &Opts) -> std::result::Result<Vec<Row>, DbError> {
+    let config = config(opts)?;
+    let named_instance = is_named_instance(&opts.connection_string);
+    let mut client = within(
+        opts.connect_timeout,
+        "Connection",
+        client(config, named_instance),
+    )
+    .await?;
+    match opts.sql_query {
+        Some(ref sql_query) => {
+            within(
+                opts.query_timeout,
+                "Query",
+                execute_statement(&mut client, sql_query),
+            )
+            .await
+        }
+        None => Ok(Vec::new()),
+    }
+}
+
+async fn execute_statement(
+    client: &mut Client<Compat<TcpStream>>,
+    sql_query: &str,
+) -> Result<Vec<Row>, DbError> {
+    let rows = client
+        .simple_query(sql_query)
+        .await?
+        .into_first_result()
+        .await?;
+    Ok(to_rows(rows))
+}
+
+fn type_name(column_type: ColumnType) -> &'static str {
+    match column_type {
+        ColumnType::Null => "null",
+        ColumnType::Bit | ColumnType::Bitn => "bit",
+        ColumnType::Int1 => "tinyint",
+        ColumnType::Int2 => "smallint",
+        ColumnType::Int4 | ColumnType::Intn => "int",
+        ColumnType::Int8 => "bigint",
+        ColumnType::Float4 => "real",
+        ColumnType::Float8 | ColumnType::Floatn => "float",
+        ColumnType::Money => "money",
+        ColumnType::Money4 => "smallmoney",
+        ColumnType::Decimaln => "decimal",
+        ColumnType::Numericn => "numeric",
+        ColumnType::Guid => "uniqueidentifier",
+        ColumnType::Datetime4 => "smalldatetime",
+        ColumnType::Datetime | ColumnType::Datetimen => "datetime",
+        ColumnType::Daten => "date",
+        ColumnType::Timen => "time",
+        ColumnType::Datetime2 => "datetime2",
+        ColumnType::DatetimeOffsetn => "datetimeoffset",
+        ColumnType::BigVarBin => "varbinary",
+        ColumnType::BigBinary => "binary",
+        ColumnType::BigVarChar => "varchar",
+        ColumnType::BigChar => "char",
+        ColumnType::NVarchar => "nvarchar",
+        ColumnType::NChar => "nchar",
+        ColumnType::Text => "text",
+        ColumnType::NText => "ntext",
+        ColumnType::Image => "image",
+        ColumnType::Xml => "xml",
+        ColumnType::Udt => "udt",
+        ColumnType::SSVariant => "sql_variant",
+    }
+}
+
+// Days from 1970-01-01 back to where SQL Server's date types count from
+const DATETIME_EPOCH_DAYS: i64 = -25_567; // 1900-01-01
+const DATE_EPOCH_DAYS: i64 = -719_162; // 0001-01-01
+
+fn timestamp(days: i64, micros: i64) -> Value {
+    match value::timestamp_from_micros(days * MICROS_PER_DAY + micros) {
+        Some(time) => Value::Timestamp(time),
+        None => Value::Null,
+    }
+}
+
+// `time` is a count of 10^-scale seconds
+fn time_micros(time: tiberius::time::Time) -> i64 {
+    let increments = time.increments() as i64;
+    match time.scale() {
+        scale if scale <= 6 => increments * 10i64.pow(6 - u32::from(scale)),
+        scale => increments / 10i64.pow(u32::from(scale) - 6),
+    }
+}
+
+fn datetime2(value: tiberius::time::DateTime2) -> Value {
+    timestamp(
+        i64::from(value.date().days()) + DATE_EPOCH_DAYS,
+        time_micros(value.time()),
+    )
+}
+
+// tiberius' own formatting loses the sign of e.g. -0.5, so put the decimal point in ourselves
+fn numeric_text(numeric: Numeric) -> String {
+    let scale = usize::from(numeric.scale());
+    let digits = format!(
+        "{:0>width$}",
+        numeric.value().unsigned_abs(),
+        width = scale + 1
+    );
+    let (whole, fraction) = digits.split_at(digits.len() - scale);
+    let sign = if numeric.value() < 0 { "-" } else { "" };
+    if fraction.is_empty() {
+        format!("{}{}", sign, whole)
+    } else {
+        format!("{}{}.{}", sign, whole, fraction)
+    }
+}
+
+fn to_value(data: ColumnData<'static>) -> Value {
+    match data {
+        ColumnData::U8(Some(v)) => Value::Int(v.into()),
+        ColumnData::I16(Some(v)) => Value::Int(v.into()),
+        ColumnData::I32(Some(v)) => Value::Int(v.into()),
+        ColumnData::I64(Some(v)) => Value::Int(v),
+        ColumnData::F32(Some(v)) => Value::Float(v.into()),
+        ColumnData::F64(Some(v)) => Value::Float(v),
+        ColumnData::Bit(Some(v)) => Value::Bool(v),
+        ColumnData::String(Some(v)) => Value::Text(v.into_owned()),
+        // As SQL Server shows them
+        ColumnData::Guid(Some(v)) => Value::Text(v.to_string().to_uppercase()),
+        ColumnData::Binary(Some(v)) => Value::Bytes(v.into_owned()),
+        ColumnData::Numeric(Some(v)) => Value::Decimal(numeric_text(v)),
+        ColumnData::Xml(Some(v)) => Value::Text(v.into_owned().into_string()),
+        // 1/300ths of a second
+        ColumnData::DateTime(Some(v)) => timestamp(
+            i64::from(v.days()) + DATETIME_EPOCH_DAYS,
+            (i64::from(v.seconds_fragments()) * 10_000 + 1) / 3,
+        ),
+        // Minutes
+        ColumnData::SmallDateTime(Some(v)) => timestamp(
+            i64::from(v.days()) + DATETIME_EPOCH_DAYS,
+            i64::from(v.seconds_fragments()) * 60_000_000,
+        ),
+        ColumnData::Date(Some(v)) => timestamp(i64::from(v.days()) + DATE_EPOCH_DAYS, 0),
+        ColumnData::Time(Some(v)) => Value::Text(value::format_time_of_day(time_micros(v))),
+        ColumnData::DateTime2(Some(v)) => datetime2(v),
+        // Sent as UTC, with the offset alongside
+        ColumnData::DateTimeOffset(Some(v)) => datetime2(v.datetime2()),
+        _ => Value::Null,
+    }
+}
+
+fn to_rows(rows: Vec<tiberius::Row>) -> Vec<Row> {
+    let columns: Arc<[Column]> = match rows.first() {
+        Some(row) => row
+            .columns()
+            .iter()
+            .map(|col| Column {
+                name: col.name().to_string(),
+                type_name: type_name(col.column_type()).to_string(),
+            })
+            .collect(),
+        None => return Vec::new(),
+    };
+    rows.into_iter()
+        .map(|row| Row::new(columns.clone(), row.into_iter().map(to_value).collect()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn utf16(text: &str) -> Vec<u8> {
+        text.encode_utf16().flat_map(|c| c.to_le_bytes()).collect()
+    }
+
+    fn b_varchar(text: &str) -> Vec<u8> {
+        [vec![text.encode_utf16().count() as u8], utf16(text)].concat()
+    }
+
+    fn us_varchar(text: &str) -> Vec<u8> {
+        [
+            (text.encode_utf16().count() as u16).to_le_bytes().to_vec(),
+            utf16(text),
+        ]
+        .concat()
+    }
+
+    // Server version and no TLS, as the client asks for `encrypt=DANGER_PLAINTEXT`
+    fn prelogin() -> Vec<u8> {
+        vec![
+            0x00, 0, 11, 0, 6, // version at 11, 6 bytes
+            0x01, 0, 17, 0, 1,    // encryption at 17, 1 byte
+            0xFF, // terminator
+            16, 0, 0x10, 0x7A, 0, 0,    // 16.0.4218
+            0x02, // encryption not supported
+        ]
+    }
+
+    fn error(code: u32, class: u8, message: &str) -> Vec<u8> {
+        let body = [
+            code.to_le_bytes().to_vec(),
+            vec![1, class],
+            us_varchar(message),
+            b_varchar("standin"),
+            b_varchar(""),
+            1u32.to_le_bytes().to_vec(),
+        ]
+        .concat();
+        [vec![0xAA], (body.len() as u16).to_le_bytes().to_vec(), body].concat()
+    }
+
+    fn done(status: u16, rows: u64) -> Vec<u8> {
+        [
+            vec![0xFD],
+            status.to_le_bytes().to_vec(),
+            0xC1u16.to_le_bytes().to_vec(),
+            rows.to_le_bytes().to_vec(),
+        ]
+        .concat()
+    }
+
+    const DONE_ERROR: u16 = 0x02;
+    const DONE_COUNT: u16 = 0x10;
+
+    // `SELECT 7 AS id, N'done' AS status, NULL AS id`
+    fn results() -> Vec<u8> {
+        let nvarchar = |name: &str| {
+            [
+                vec![0, 0, 0, 0, 1, 0, 0xE7, 200, 0, 0x09, 0x04, 0xD0, 0x00, 0x34],
+                b_varchar(name),
+            ]
+            .concat()
+        };
+        [
+            vec![0x81, 3, 0],
+            // int
+            [vec![0, 0, 0, 0, 1, 0, 0x26, 4], b_varchar("id")].concat(),
+            nvarchar("status"),
+            nvarchar("id"),
+            vec![0xD1, 4],
+            7i32.to_le_bytes().to_vec(),
+            (utf16("done").len() as u16).to_le_bytes().to_vec(),
+            utf16("done"),
+            vec![0xFF, 0xFF],
+            done(DONE_COUNT, 1),
+        ]
+        .concat()
+    }
+
+    fn read_message(stream: &mut impl Read) -> Option<()> {
+        loop {
+            let mut header = [0; 8];
+            stream.read_exact(&mut header).ok()?;
+            let mut body = vec![0; u16::from_be_bytes([header[2], header[3]]) as usize - 8];
+            stream.read_exact(&mut body).ok()?;
+            // end of message
+            if header[1] & 0x01 != 0 {
+                return Some(());
+            }
+        }
+    }
+
+    /// A scripted SQL Server, which sends back each of the replies in turn for each message it gets
+    fn stand_in(replies: Vec<Vec<u8>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            for (id, reply) in replies.into_iter().enumerate() {
+                if read_message(&mut stream).is_none() {
+                    return;
+                }
+                let mut packet = vec![0x04, 0x01];
+                packet.extend(((reply.len() + 8) as u16).to_be_bytes());
+                packet.extend([0, 0, id as u8 + 1, 0]);
+                packet.extend(reply);
+                stream.write_all(&packet).unwrap();
+            }
+            // Wait for the client to hang up
+            let _ = stream.read(&mut [0; 1]);
+        });
+        format!(
+            "server=tcp:127.0.0.1,{};user=sa;password=secret;encrypt=DANGER_PLAINTEXT",
+            port
+        )
+    }
+
+    fn server_error(code: u32, class: u8, message: &str) -> DbError {
+        let connection_string = stand_in(vec![
+            prelogin(),
+            [error(code, class, message), done(DONE_ERROR, 0)].concat(),
+        ]);
+        connect(&Opts::new().connection_string(connection_string)).unwrap_err()
+    }
+
+    #[test]
+    fn test_mssql_login_errors() {
+        let err = server_error(18456, 14, "Login failed for user 'sa'.");
+        assert_eq!(err.kind, DbErrorLifetime::Permanent, "{:?}", err);
+        assert_eq!(err.native_code(), Some(18456));
+        assert_eq!(err.message(), "Login failed for user 'sa'.");
+        assert_eq!(
+            err.to_string(),
+            "Login failed for user 'sa'. (native error 18456)"
+        );
+        assert_eq!(
+            crate::common::exit_code(&err),
+            exitcode::NOPERM,
+            "{:?}",
+            err
+        );
+
+        let err = server_error(
+            4060,
+            11,
+            "Cannot open database \"app\" requested by the login.",
+        );
+        assert_eq!(err.kind, DbErrorLifetime::Temporary, "{:?}", err);
+    }
+
+    #[test]
+    fn test_mssql_query_results() {
+        let connection_string = stand_in(vec![prelogin(), done(0, 0), results()]);
+        let rows = connect(
+            &Opts::new()
+                .connection_string(connection_string)
+                .sql_query("SELECT 7 AS id, N'done' AS status, NULL AS id"),
+        )
+        .unwrap();
+        assert_eq!(rows.len(), 1);
+        let columns: Vec<(&str, &str)> = rows[0]
+            .columns()
+            .iter()
+            .map(|c| (c.name.as_str(), c.type_name.as_str()))
+            .collect();
+        assert_eq!(
+            columns,
+            [("id", "int"), ("status", "nvarchar"), ("id", "nvarchar")]
+        );
+        assert_eq!(rows[0]["id"], Value::Int(7));
+        assert_eq!(rows[0]["status"], Value::Text("done".to_string()));
+        assert_eq!(rows[0][2], Value::Null);
+    }
+
+    #[test]
+    fn test_mssql_syntax_error() {
+        let connection_string = stand_in(vec![
+            prelogin(),
+            done(0, 0),
+            [
+                error(102, 15, "Incorrect syntax near 'SELEC'."),
+                done(DONE_ERROR, 0),
+            ]
+            .concat(),
+        ]);
+        let err = connect(
+            &Opts::new()
+                .connection_string(connection_string)
+                .sql_query("SELEC 1"),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind, DbErrorLifetime::Permanent, "{:?}", err);
+        assert_eq!(crate::common::exit_code(&err), exitcode::DATAERR);
+    }
+
+    #[test]
+    fn test_mssql_values() {
+        assert_eq!(
+            to_value(ColumnData::DateTime(Some(tiberius::time::DateTime::new(
+                45_290, 300
+            ))))
+            .to_string(),
+            "2024-01-01T00:00:01Z"
+        );
+        assert_eq!(
+            to_value(ColumnData::Date(Some(tiberius::time::Date::new(0)))).to_string(),
+            "0001-01-01T00:00:00Z"
+        );
+        assert_eq!(
+            to_value(ColumnData::Time(Some(tiberius::time::Time::new(
+                37_230_500_000,
+                7
+            )))),
+            Value::Text("01:02:03.050000".to_string())
+        );
+        assert_eq!(to_value(ColumnData::I32(None)), Value::Null);
+        assert_eq!(
+            to_value(ColumnData::Numeric(Some(Numeric::new_with_scale(1250, 2)))),
+            Value::Decimal("12.50".to_string())
+        );
+        assert_eq!(numeric_text(Numeric::new_with_scale(-5, 3)), "-0.005");
+        assert_eq!(
+            numeric_text(Numeric::new_with_scale(1_234_567_890_123_456_789, 2)),
+            "12345678901234567.89"
+        );
+        assert_eq!(numeric_text(Numeric::new_with_scale(42, 0)), "42");
+    }
+
+    #[test]
+    fn test_mssql_with_no_server() {
+        let mut opts =
+            Opts::new().connection_string("server=tcp:localhost,1;user=sa;password=secret");
+        opts.connect_timeout = Some(Duration::from_secs(5));
+        let err = connect(&opts).unwrap_err();
+        assert_eq!(err.kind, DbErrorLifetime::Temporary, "{:?}", err);
+    }
+
+    #[test]
+    fn test_mssql_named_instances() {
+        assert!(is_named_instance("server=tcp:db\\SQLEXPRESS;user=sa"));
+        assert!(!is_named_instance("server=tcp:db,1433;password={a\\b}"));
+        assert!(is_named_instance("jdbc:sqlserver://db\\SQLEXPRESS;user=sa"));
+        assert!(!is_named_instance("jdbc:sqlserver://db:1433;password=a\\b"));
+    }
+
+    #[test]
+    fn test_mssql_with_bad_connection_string() {
+        let err = connect(&Opts::new().connection_string("server=tcp:localhost,port")).unwrap_err();
+        assert_eq!(err.kind, DbErrorLifetime::Permanent, "{:?}", err);
+
+        let err = connect(
+            &Opts::new()
+                .connection_string("server=tcp:localhost;TrustServerCertificate=true")
+                .ca_file("/tmp/ca.pem"),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind, DbErrorLifetime::Permanent, "{:?}", err);
+    }
+}
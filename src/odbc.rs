@@ -4,7 +4,9 @@ use odbc_api::{
 };
 
 use crate::common::{DbError, DbErrorLifetime, DbErrorType, Opts};
-use std::collections::HashMap;
+use crate::value::{Column, Row, TextKind, Value};
+use log::debug;
+use std::{sync::Arc, time::Duration};
 
 impl From<Error> for DbError {
     fn from(item: Error) -> Self {
@@ -19,6 +21,8 @@ impl From<Error> for DbError {
                 .to_string();
 
             let kind = match state.as_str() {
+                // bad user/password
+                c if c.starts_with("28") => DbErrorLifetime::Permanent,
                 "IM002"  // no driver
                 | "01000" // no driver at path
                 | "IM004" // bad driver
@@ -35,12 +39,26 @@ impl From<Error> for DbError {
     }
 }
 
-pub fn connect(opts: &Opts) -> std::result::Result<Vec<HashMap<String, String>>, DbError> {
+// ODBC timeouts are whole seconds, and zero means "no timeout", so round up
+fn timeout_seconds(timeout: Duration) -> u32 {
+    (timeout.as_secs() + u64::from(timeout.subsec_nanos() > 0)).clamp(1, u32::MAX.into()) as u32
+}
+
+pub fn connect(opts: &Opts) -> std::result::Result<Vec<Row>, DbError> {
     let env = Environment::new()?;
-    let conn =
-        env.connect_with_connection_string(&opts.connection_string, ConnectionOptions::default())?;
+    let conn = env.connect_with_connection_string(
+        &opts.connection_string,
+        ConnectionOptions {
+            login_timeout_sec: opts.connect_timeout.map(timeout_seconds),
+            ..ConnectionOptions::default()
+        },
+    )?;
     if let Some(ref sql_query) = opts.sql_query {
-        execute_statement(&conn, sql_query)
+        execute_statement(
+            &conn,
+            sql_query,
+            opts.query_timeout.map(|t| timeout_seconds(t) as usize),
+        )
     } else {
         Ok(Vec::new())
     }
@@ -48,15 +66,65 @@ pub fn connect(opts: &Opts) -> std::result::Result<Vec<HashMap<String, String>>,
 
 const BATCH_SIZE: usize = 100000;
 
+// Everything is fetched as text, so this is how to read it back
+fn text_kind(data_type: &DataType) -> TextKind {
+    match data_type {
+        DataType::Bit => TextKind::Bool,
+        DataType::TinyInt | DataType::SmallInt | DataType::Integer | DataType::BigInt => {
+            TextKind::Int
+        }
+        DataType::Real | DataType::Float { .. } | DataType::Double => TextKind::Float,
+        DataType::Numeric { .. } | DataType::Decimal { .. } => TextKind::Decimal,
+        DataType::Date | DataType::Timestamp { .. } => TextKind::Timestamp,
+        DataType::Binary { .. } | DataType::Varbinary { .. } | DataType::LongVarbinary { .. } => {
+            TextKind::Bytes
+        }
+        _ => TextKind::Text,
+    }
+}
+
+// The SQL name for the type, as the value's text doesn't say what it was
+fn type_name(data_type: &DataType) -> String {
+    let name = match data_type {
+        DataType::Unknown => "UNKNOWN",
+        DataType::Char { .. } => "CHAR",
+        DataType::WChar { .. } => "WCHAR",
+        DataType::Numeric { .. } => "NUMERIC",
+        DataType::Decimal { .. } => "DECIMAL",
+        DataType::Integer => "INTEGER",
+        DataType::SmallInt => "SMALLINT",
+        DataType::Float { .. } => "FLOAT",
+        DataType::Real => "REAL",
+        DataType::Double => "DOUBLE",
+        DataType::Varchar { .. } => "VARCHAR",
+        DataType::WVarchar { .. } => "WVARCHAR",
+        DataType::LongVarchar { .. } => "LONGVARCHAR",
+        DataType::WLongVarchar { .. } => "WLONGVARCHAR",
+        DataType::LongVarbinary { .. } => "LONGVARBINARY",
+        DataType::Date => "DATE",
+        DataType::Time { .. } => "TIME",
+        DataType::Timestamp { .. } => "TIMESTAMP",
+        DataType::BigInt => "BIGINT",
+        DataType::TinyInt => "TINYINT",
+        DataType::Bit => "BIT",
+        DataType::Varbinary { .. } => "VARBINARY",
+        DataType::Binary { .. } => "BINARY",
+        DataType::Other { data_type, .. } => return format!("SQL type {}", data_type.0),
+    };
+    name.to_string()
+}
+
 fn execute_statement(
     conn: &Connection,
     sql_query: &str,
-) -> Result<Vec<HashMap<String, String>>, DbError> {
-    let mut results: Vec<HashMap<String, String>> = Vec::new();
-    match conn.execute(sql_query, (), None)? {
+    query_timeout_sec: Option<usize>,
+) -> Result<Vec<Row>, DbError> {
+    let mut results: Vec<Row> = Vec::new();
+    match conn.execute(sql_query, (), query_timeout_sec)? {
         Some(mut cursor) => {
             let col_count = cursor.num_result_cols()? as u16;
-            let mut cols: HashMap<usize, String> = HashMap::new();
+            let mut columns: Vec<Column> = Vec::new();
+            let mut kinds: Vec<TextKind> = Vec::new();
             for i in 1..(col_count + 1) {
                 let mut cd = ColumnDescription {
                     name: Vec::new(),
@@ -64,33 +132,31 @@ fn execute_statement(
                     nullability: Nullability::Unknown,
                 };
                 cursor.describe_col(i, &mut cd)?;
-                cols.insert((i - 1).into(), cd.name_to_string().unwrap());
+                columns.push(Column {
+                    name: cd.name_to_string().unwrap(),
+                    type_name: type_name(&cd.data_type),
+                });
+                kinds.push(text_kind(&cd.data_type));
             }
+            let columns: Arc<[Column]> = columns.into();
             let mut buffers = TextRowSet::for_cursor(BATCH_SIZE, &mut cursor, None)?;
             let mut row_set_cursor = cursor.bind_buffer(&mut buffers)?;
             while let Some(batch) = row_set_cursor.fetch()? {
                 // Within a batch, iterate over every row
                 for row_index in 0..batch.num_rows() {
-                    let mut result: HashMap<String, String> = HashMap::new();
-                    for col_index in 0..batch.num_cols() {
-                        let item = batch.at(col_index, row_index);
-                        match item {
-                            Some(val) => {
-                                result.insert(
-                                    cols[&col_index].clone(),
-                                    std::str::from_utf8(val).unwrap().to_string(),
-                                );
-                            }
-                            None => {
-                                result.insert(cols[&col_index].clone(), "".to_string());
-                            }
-                        }
-                    }
-                    results.push(result);
+                    let values = kinds
+                        .iter()
+                        .enumerate()
+                        .map(|(col_index, kind)| match batch.at(col_index, row_index) {
+                            Some(val) => Value::parse_as(&String::from_utf8_lossy(val), *kind),
+                            None => Value::Null,
+                        })
+                        .collect();
+                    results.push(Row::new(columns.clone(), values));
                 }
             }
         }
-        None => println!("Query executed, no data returned"),
+        None => debug!("Query executed, no data returned"),
     }
 
     Ok(results)
@@ -112,6 +178,14 @@ pub fn postgres_connect() -> String {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::common::FailureCategory;
+
+    #[test]
+    fn test_timeout_seconds() {
+        assert_eq!(timeout_seconds(Duration::from_millis(200)), 1);
+        assert_eq!(timeout_seconds(Duration::from_secs(2)), 2);
+        assert_eq!(timeout_seconds(Duration::from_millis(2500)), 3);
+    }
 
     #[test]
     fn test_connect_no_driver() {
@@ -123,6 +197,7 @@ mod test {
     fn test_connect_with_missing_driver() {
         let err = connect(&Opts::new().connection_string("Driver=foo;Server=blah;")).unwrap_err();
         assert_eq!(err.kind, DbErrorLifetime::Permanent, "{:?}", err);
+        assert_eq!(err.category(), FailureCategory::DriverMissing, "{:?}", err);
         if let DbErrorType::OdbcError { error } = err.error {
             let desc = format!("{error}");
             if cfg!(target_os = "linux") {
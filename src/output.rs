@@ -0,0 +1,575 @@
+use crate::common::{DbError, DbErrorLifetime, DbErrorType, DbMode};
+use crate::credentials::redact;
+use crate::value::{Row, Value as DbValue};
+use log::{debug, error, info};
+#[cfg(feature = "odbc")]
+use odbc_api::handles::slice_to_utf8;
+use serde_json::{json, Value};
+use std::{time::Duration, time::SystemTime};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl OutputFormat {
+    pub(crate) fn from_str(s: &str) -> OutputFormat {
+        match s.to_lowercase().as_str() {
+            "json" => OutputFormat::Json,
+            _ => OutputFormat::Text,
+        }
+    }
+}
+
+/// How the query results are printed to stdout once the wait succeeds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResultFormat {
+    Table,
+    Csv,
+    Tsv,
+    Json,
+    /// Just the value from a one-row, one-column result
+    Scalar,
+}
+
+impl ResultFormat {
+    pub(crate) fn from_str(s: &str) -> ResultFormat {
+        match s.to_lowercase().as_str() {
+            "csv" => ResultFormat::Csv,
+            "tsv" => ResultFormat::Tsv,
+            "json" => ResultFormat::Json,
+            "scalar" => ResultFormat::Scalar,
+            _ => ResultFormat::Table,
+        }
+    }
+}
+
+// NULLs are empty in machine-readable formats, so they're easy to test for in scripts
+fn cell(value: &DbValue) -> String {
+    match value {
+        DbValue::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+// As Postgres' text COPY format, so tabs and newlines can't break up the columns
+fn tsv_field(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn delimited(rows: &[Row], separator: &str, field: fn(&str) -> String) -> String {
+    let first = match rows.first() {
+        Some(first) => first,
+        None => return String::new(),
+    };
+    let line = |fields: Vec<String>| fields.join(separator) + "\n";
+    let mut output = line(first.columns().iter().map(|c| field(&c.name)).collect());
+    for row in rows {
+        output += &line(row.values().iter().map(|v| field(&cell(v))).collect());
+    }
+    output
+}
+
+fn table(rows: &[Row]) -> String {
+    let first = match rows.first() {
+        Some(first) => first,
+        None => return "(0 rows)\n".to_string(),
+    };
+    let header: Vec<String> = first.columns().iter().map(|c| c.name.clone()).collect();
+    let cells: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| row.values().iter().map(|v| v.to_string()).collect())
+        .collect();
+    let widths: Vec<usize> = (0..header.len())
+        .map(|i| {
+            cells
+                .iter()
+                .map(|row| row[i].chars().count())
+                .chain([header[i].chars().count()])
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    let line = |fields: &[String]| {
+        let padded: Vec<String> = fields
+            .iter()
+            .zip(widths.iter())
+            .map(|(field, width)| format!("{:<width$}", field, width = width))
+            .collect();
+        padded.join(" | ").trim_end().to_string() + "\n"
+    };
+    let mut output = line(&header);
+    output += &(widths
+        .iter()
+        .map(|width| "-".repeat(*width))
+        .collect::<Vec<String>>()
+        .join("-+-")
+        + "\n");
+    for row in &cells {
+        output += &line(row);
+    }
+    output
+        + &format!(
+            "({} {})\n",
+            rows.len(),
+            if rows.len() == 1 { "row" } else { "rows" }
+        )
+}
+
+// Rows from different queries (i.e. several targets) have different columns
+fn by_columns(rows: &[Row]) -> Vec<&[Row]> {
+    let mut groups = Vec::new();
+    let mut start = 0;
+    for index in 1..=rows.len() {
+        if index == rows.len() || rows[index].columns() != rows[start].columns() {
+            groups.push(&rows[start..index]);
+            start = index;
+        }
+    }
+    groups
+}
+
+/// Query results as they should be printed, or why they can't be (e.g. more than one value for `Scalar`)
+///
+/// Tables and CSV/TSV get a new header whenever the columns change.
+pub fn format_results(format: ResultFormat, rows: &[Row]) -> Result<String, String> {
+    let each_group = |print: &dyn Fn(&[Row]) -> String| {
+        if rows.is_empty() {
+            return print(rows);
+        }
+        by_columns(rows).into_iter().map(print).collect()
+    };
+    Ok(match format {
+        ResultFormat::Table => each_group(&table),
+        ResultFormat::Csv => each_group(&|rows| delimited(rows, ",", csv_field)),
+        ResultFormat::Tsv => each_group(&|rows| delimited(rows, "\t", tsv_field)),
+        ResultFormat::Json => json!(rows).to_string() + "\n",
+        ResultFormat::Scalar => match rows {
+            [row] if row.values().len() == 1 => cell(&row[0]) + "\n",
+            _ => {
+                return Err(format!(
+                    "Expected one row with one column for a scalar result, but got {} rows with {} columns",
+                    rows.len(),
+                    rows.first().map(|row| row.values().len()).unwrap_or_default()
+                ))
+            }
+        },
+    })
+}
+
+/// What's happening after a failed attempt
+pub enum NextStep {
+    Pausing(Duration),
+    OutOfTime,
+    GivingUp,
+}
+
+// Some errors (e.g. postgres) only give the detail in their sources
+#[cfg(feature = "postgres")]
+fn with_sources(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(e) = source {
+        message = format!("{}: {}", message, e);
+        source = e.source();
+    }
+    message
+}
+
+/// SQLSTATE/error code, native error number and message for an error
+pub fn error_details(error: &DbErrorType) -> (Option<String>, Option<i64>, String) {
+    match error {
+        #[cfg(feature = "odbc")]
+        DbErrorType::OdbcError { error } => (
+            Some(error.state.as_str().to_string()),
+            Some(error.native_error.into()),
+            slice_to_utf8(&error.message).unwrap_or_default(),
+        ),
+        #[cfg(feature = "postgres")]
+        DbErrorType::PostgresError { error } => {
+            let db_error = error
+                .downcast_ref::<postgres::error::DbError>()
+                .or_else(|| {
+                    error
+                        .downcast_ref::<postgres::error::Error>()
+                        .and_then(|e| e.as_db_error())
+                });
+            match db_error {
+                Some(db_error) => (
+                    Some(db_error.code().code().to_string()),
+                    None,
+                    db_error.message().to_string(),
+                ),
+                None => (None, None, with_sources(error.as_ref())),
+            }
+        }
+        #[cfg(feature = "mysql")]
+        DbErrorType::MysqlError { error } => match error {
+            mysql::Error::MySqlError(e) => (
+                Some(e.state.clone()),
+                Some(e.code.into()),
+                e.message.clone(),
+            ),
+            other => (None, None, other.to_string()),
+        },
+        // TDS doesn't send a SQLSTATE, just SQL Server's own error number
+        #[cfg(feature = "mssql")]
+        DbErrorType::MssqlError { error } => match error {
+            tiberius::error::Error::Server(e) => {
+                (None, Some(e.code().into()), e.message().to_string())
+            }
+            other => (None, None, other.to_string()),
+        },
+        // No SQLSTATE either, but the extended result code says more than the message
+        #[cfg(feature = "sqlite")]
+        DbErrorType::SqliteError { error } => match error {
+            rusqlite::Error::SqliteFailure(e, message) => (
+                None,
+                Some(e.extended_code.into()),
+                message.clone().unwrap_or_else(|| e.to_string()),
+            ),
+            rusqlite::Error::SqlInputError { error, msg, .. } => {
+                (None, Some(error.extended_code.into()), msg.clone())
+            }
+            other => (None, None, other.to_string()),
+        },
+        #[cfg(feature = "postgres")]
+        DbErrorType::TlsError { error } => (None, None, with_sources(error.as_ref())),
+        DbErrorType::UrlError { error } => (None, None, error.to_string()),
+        DbErrorType::RowCountError {
+            rows,
+            min_rows,
+            max_rows,
+        } => (
+            None,
+            None,
+            match max_rows {
+                Some(max_rows) => format!(
+                    "Query returned {} rows, expected between {} and {}",
+                    rows, min_rows, max_rows
+                ),
+                None => format!(
+                    "Query returned {} rows, expected at least {}",
+                    rows, min_rows
+                ),
+            },
+        ),
+        DbErrorType::ExpectationError { description } => (None, None, description.clone()),
+    }
+}
+
+/// Prints what happened for each attempt, in the chosen format
+/// Text goes out via the logger (so it follows the verbosity), JSON is always printed
+pub struct Reporter {
+    format: OutputFormat,
+    mode: &'static str,
+    target: Option<String>,
+    quiet: bool,
+    attempt: u32,
+    attempt_started: Duration,
+}
+
+impl Reporter {
+    pub fn new(format: OutputFormat, mode: &DbMode, quiet: bool) -> Self {
+        Reporter {
+            format,
+            mode: mode.name(),
+            target: None,
+            quiet,
+            attempt: 0,
+            attempt_started: Duration::ZERO,
+        }
+    }
+
+    /// Labels everything with the target's name, for when we're waiting on several databases
+    pub fn with_target(mut self, name: &str) -> Self {
+        self.target = Some(name.to_string());
+        self
+    }
+
+    fn prefix(&self) -> String {
+        match &self.target {
+            Some(name) => format!("[{}] ", name),
+            None => String::new(),
+        }
+    }
+
+    // With several targets, each one's outcome is followed by an overall result
+    fn result_type(&self) -> &'static str {
+        match self.target {
+            Some(_) => "target",
+            None => "result",
+        }
+    }
+
+    pub fn starting(&mut self, elapsed: Duration) {
+        self.attempt_started = elapsed;
+        debug!(
+            "{}Attempt {} starting ({:.3}s elapsed)",
+            self.prefix(),
+            self.attempt + 1,
+            elapsed.as_secs_f64()
+        );
+    }
+
+    fn finished(&mut self, elapsed: Duration) {
+        self.attempt += 1;
+        debug!(
+            "{}Attempt {} took {:.3}s",
+            self.prefix(),
+            self.attempt,
+            elapsed.saturating_sub(self.attempt_started).as_secs_f64()
+        );
+    }
+
+    fn base(&self, kind: &str, elapsed: Duration) -> serde_json::Map<String, Value> {
+        let mut object = serde_json::Map::new();
+        object.insert("type".to_string(), json!(kind));
+        object.insert(
+            "timestamp".to_string(),
+            json!(humantime::format_rfc3339_millis(SystemTime::now()).to_string()),
+        );
+        object.insert("attempt".to_string(), json!(self.attempt));
+        object.insert("elapsed".to_string(), json!(elapsed.as_secs_f64()));
+        object.insert("mode".to_string(), json!(self.mode));
+        if let Some(name) = &self.target {
+            object.insert("target".to_string(), json!(name));
+        }
+        object
+    }
+
+    fn print(object: serde_json::Map<String, Value>) {
+        println!("{}", Value::Object(object));
+    }
+
+    pub fn success(&mut self, elapsed: Duration, show_rows: bool, results: &[Row]) {
+        self.finished(elapsed);
+        match self.format {
+            OutputFormat::Text => {
+                if show_rows {
+                    info!("{}Success {}", self.prefix(), json!(results));
+                } else {
+                    info!("{}Success", self.prefix());
+                }
+            }
+            OutputFormat::Json => {
+                if !self.quiet {
+                    let mut attempt = self.base("attempt", elapsed);
+                    attempt.insert("success".to_string(), json!(true));
+                    Reporter::print(attempt);
+                }
+                let mut result = self.base(self.result_type(), elapsed);
+                result.insert("success".to_string(), json!(true));
+                result.insert("rows".to_string(), json!(results));
+                Reporter::print(result);
+            }
+        }
+    }
+
+    pub fn failure(&mut self, elapsed: Duration, dberror: &DbError, next: NextStep) {
+        self.finished(elapsed);
+        match self.format {
+            OutputFormat::Text => match next {
+                NextStep::GivingUp => {
+                    error!("{}Permanent error: {}", self.prefix(), dberror)
+                }
+                NextStep::OutOfTime => error!(
+                    "{}Temporary error (exiting as out of time): {}",
+                    self.prefix(),
+                    dberror
+                ),
+                NextStep::Pausing(pause) => info!(
+                    "{}Temporary error (pausing for {}): {}",
+                    self.prefix(),
+                    crate::retry::describe_pause(pause),
+                    dberror
+                ),
+            },
+            OutputFormat::Json => {
+                let (code, native_code, message) = error_details(&dberror.error);
+                let message = redact(&message);
+                let mut attempt = self.base("attempt", elapsed);
+                attempt.insert("success".to_string(), json!(false));
+                attempt.insert(
+                    "lifetime".to_string(),
+                    json!(match dberror.kind {
+                        DbErrorLifetime::Permanent => "permanent",
+                        DbErrorLifetime::Temporary => "temporary",
+                    }),
+                );
+                attempt.insert("code".to_string(), json!(code));
+                attempt.insert("native_code".to_string(), json!(native_code));
+                attempt.insert("message".to_string(), json!(message));
+                if let NextStep::Pausing(pause) = next {
+                    if !self.quiet {
+                        attempt.insert("pause".to_string(), json!(pause.as_secs_f64()));
+                        Reporter::print(attempt);
+                    }
+                    return;
+                }
+                Reporter::print(attempt);
+                let mut result = self.base(self.result_type(), elapsed);
+                result.insert("success".to_string(), json!(false));
+                result.insert(
+                    "reason".to_string(),
+                    json!(match next {
+                        NextStep::OutOfTime => "timeout",
+                        _ => "permanent",
+                    }),
+                );
+                result.insert("message".to_string(), json!(message));
+                Reporter::print(result);
+            }
+        }
+    }
+
+    /// The overall deadline was hit while an attempt was still running
+    pub fn deadline(&mut self, elapsed: Duration) {
+        self.finished(elapsed);
+        let message = "Exiting as out of time during attempt";
+        match self.format {
+            OutputFormat::Text => error!("{}{}", self.prefix(), message),
+            OutputFormat::Json => {
+                let mut result = self.base(self.result_type(), elapsed);
+                result.insert("success".to_string(), json!(false));
+                result.insert("reason".to_string(), json!("timeout"));
+                result.insert("message".to_string(), json!(message));
+                Reporter::print(result);
+            }
+        }
+    }
+}
+
+/// Overall result when waiting on several databases, with whether each one was ready
+pub fn summary(format: OutputFormat, elapsed: Duration, any: bool, targets: &[(String, bool)]) {
+    let success = if any {
+        targets.iter().any(|(_, ready)| *ready)
+    } else {
+        targets.iter().all(|(_, ready)| *ready)
+    };
+    match format {
+        OutputFormat::Text => {
+            let status = targets
+                .iter()
+                .map(|(name, ready)| {
+                    format!("{}: {}", name, if *ready { "ready" } else { "not ready" })
+                })
+                .collect::<Vec<String>>()
+                .join(", ");
+            if success {
+                info!("Success ({})", status);
+            } else {
+                error!("Failure ({})", status);
+            }
+        }
+        OutputFormat::Json => {
+            let mut result = serde_json::Map::new();
+            result.insert("type".to_string(), json!("result"));
+            result.insert(
+                "timestamp".to_string(),
+                json!(humantime::format_rfc3339_millis(SystemTime::now()).to_string()),
+            );
+            result.insert("elapsed".to_string(), json!(elapsed.as_secs_f64()));
+            result.insert("success".to_string(), json!(success));
+            result.insert(
+                "targets".to_string(),
+                json!(targets
+                    .iter()
+                    .map(|(name, ready)| json!({"name": name, "ready": ready}))
+                    .collect::<Vec<Value>>()),
+            );
+            Reporter::print(result);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_error_details() {
+        let (code, native_code, message) = error_details(&DbErrorType::RowCountError {
+            rows: 0,
+            min_rows: 1,
+            max_rows: None,
+        });
+        assert_eq!(code, None);
+        assert_eq!(native_code, None);
+        assert_eq!(message, "Query returned 0 rows, expected at least 1");
+    }
+
+    #[test]
+    fn test_format_results() {
+        let rows: Vec<Row> = vec![
+            vec![
+                ("id", DbValue::Int(1)),
+                ("name", DbValue::Text("a, \"b\"".to_string())),
+            ]
+            .into_iter()
+            .collect(),
+            vec![("id", DbValue::Int(22)), ("name", DbValue::Null)]
+                .into_iter()
+                .collect(),
+        ];
+        let format = |format| format_results(format, &rows).unwrap();
+        assert_eq!(
+            format(ResultFormat::Table),
+            "id | name\n---+-------\n1  | a, \"b\"\n22 | NULL\n(2 rows)\n"
+        );
+        assert_eq!(
+            format(ResultFormat::Csv),
+            "id,name\n1,\"a, \"\"b\"\"\"\n22,\n"
+        );
+        assert_eq!(format(ResultFormat::Tsv), "id\tname\n1\ta, \"b\"\n22\t\n");
+        assert_eq!(
+            format(ResultFormat::Json),
+            "[{\"id\":1,\"name\":\"a, \\\"b\\\"\"},{\"id\":22,\"name\":null}]\n"
+        );
+        assert!(format_results(ResultFormat::Scalar, &rows).is_err());
+        assert_eq!(
+            format_results(ResultFormat::Scalar, &rows[1..]).unwrap_err(),
+            "Expected one row with one column for a scalar result, but got 1 rows with 2 columns"
+        );
+        let version: Row = vec![("version", DbValue::Text("2.1".to_string()))]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            format_results(ResultFormat::Scalar, std::slice::from_ref(&version)).unwrap(),
+            "2.1\n"
+        );
+        let mut several = rows.clone();
+        several.push(version);
+        assert_eq!(
+            format_results(ResultFormat::Csv, &several).unwrap(),
+            "id,name\n1,\"a, \"\"b\"\"\"\n22,\nversion\n2.1\n"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "mysql")]
+    fn test_mysql_error_details() {
+        let (code, native_code, message) = error_details(&DbErrorType::MysqlError {
+            error: mysql::Error::MySqlError(mysql::MySqlError {
+                state: "42000".to_string(),
+                message: "You have an error in your SQL syntax".to_string(),
+                code: 1064,
+            }),
+        });
+        assert_eq!(code, Some("42000".to_string()));
+        assert_eq!(native_code, Some(1064));
+        assert_eq!(message, "You have an error in your SQL syntax");
+    }
+}
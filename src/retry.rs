@@ -0,0 +1,297 @@
+use crate::common::Opts;
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backoff {
+    Fixed,
+    Linear,
+    Exponential,
+    DecorrelatedJitter,
+}
+
+impl Backoff {
+    pub(crate) fn from_str(s: &str) -> Backoff {
+        match s.to_lowercase().as_str() {
+            "linear" => Backoff::Linear,
+            "exponential" => Backoff::Exponential,
+            "decorrelated-jitter" => Backoff::DecorrelatedJitter,
+            _ => Backoff::Fixed,
+        }
+    }
+}
+
+/// Works out how long to pause between attempts, and whether there's time for another one
+pub struct Scheduler {
+    backoff: Backoff,
+    pause: Duration,
+    max_pause: Option<Duration>,
+    timeout: Option<Duration>,
+    start: Instant,
+    attempts: u32,
+    last_pause: Duration,
+    rng_state: u64,
+}
+
+impl Scheduler {
+    pub fn new(
+        backoff: Backoff,
+        pause: Duration,
+        max_pause: Option<Duration>,
+        timeout: Option<Duration>,
+    ) -> Self {
+        // Seed from the randomly-keyed std hasher, so we don't need a rand dependency
+        let seed = RandomState::new().build_hasher().finish();
+        Scheduler {
+            backoff,
+            pause,
+            max_pause,
+            timeout,
+            start: Instant::now(),
+            attempts: 0,
+            last_pause: pause,
+            rng_state: seed | 1,
+        }
+    }
+
+    pub fn from_opts(opts: &Opts) -> Self {
+        Scheduler::new(opts.backoff, opts.pause, opts.max_pause, opts.timeout)
+    }
+
+    /// A scheduler for one of several targets, with the same start time so they share a deadline
+    ///
+    /// Each gets its own jitter, so targets on the same host don't all retry at the same moment.
+    pub fn for_target(&self, index: usize) -> Self {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(self.rng_state);
+        hasher.write_usize(index);
+        Scheduler {
+            backoff: self.backoff,
+            pause: self.pause,
+            max_pause: self.max_pause,
+            timeout: self.timeout,
+            start: self.start,
+            attempts: self.attempts,
+            last_pause: self.last_pause,
+            rng_state: hasher.finish() | 1,
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Time left before the overall timeout, or None if there isn't one
+    pub fn remaining(&self) -> Option<Duration> {
+        self.timeout.map(|t| t.saturating_sub(self.elapsed()))
+    }
+
+    pub fn expired(&self) -> bool {
+        self.timeout.is_some_and(|t| self.elapsed() >= t)
+    }
+
+    /// Pause before the next attempt, or None if it won't fit into the remaining time
+    pub fn next_pause(&mut self) -> Option<Duration> {
+        self.next_pause_at(self.elapsed())
+    }
+
+    pub fn next_pause_at(&mut self, elapsed: Duration) -> Option<Duration> {
+        let pause = self.calculate_pause();
+        self.attempts += 1;
+        self.last_pause = pause;
+        if let Some(t) = self.timeout {
+            if t.saturating_sub(elapsed) < pause {
+                return None;
+            }
+        }
+        Some(pause)
+    }
+
+    fn calculate_pause(&mut self) -> Duration {
+        let pause = match self.backoff {
+            Backoff::Fixed => self.pause,
+            Backoff::Linear => self.pause.saturating_mul(self.attempts.saturating_add(1)),
+            Backoff::Exponential => self
+                .pause
+                .saturating_mul(2u32.saturating_pow(self.attempts.min(31))),
+            Backoff::DecorrelatedJitter => {
+                // See https://aws.amazon.com/blogs/architecture/exponential-backoff-and-jitter/
+                let upper = self.last_pause.saturating_mul(3).max(self.pause);
+                let spread = (upper - self.pause).as_secs_f64();
+                self.pause + Duration::from_secs_f64(spread * self.random())
+            }
+        };
+        match self.max_pause {
+            Some(max_pause) => pause.min(max_pause),
+            None => pause,
+        }
+    }
+
+    // xorshift64*, mapped onto [0, 1)
+    fn random(&mut self) -> f64 {
+        self.rng_state ^= self.rng_state >> 12;
+        self.rng_state ^= self.rng_state << 25;
+        self.rng_state ^= self.rng_state >> 27;
+        let value = self.rng_state.wrapping_mul(0x2545_F491_4F6C_DD1D);
+        (value >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Runs an attempt on another thread so we can give up on it if it's still going at the deadline
+pub fn run_with_deadline<T, F>(deadline: Option<Duration>, attempt: F) -> Option<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    match deadline {
+        None => Some(attempt()),
+        Some(deadline) => {
+            let (sender, receiver) = mpsc::channel();
+            thread::spawn(move || {
+                // Receiver may have given up on us already, so ignore send failures
+                let _ = sender.send(attempt());
+            });
+            receiver.recv_timeout(deadline).ok()
+        }
+    }
+}
+
+pub fn describe_pause(pause: Duration) -> String {
+    if pause.subsec_nanos() == 0 {
+        let secs = pause.as_secs();
+        format!("{} second{}", secs, if secs == 1 { "" } else { "s" })
+    } else {
+        // Jitter gives us nanosecond precision, which isn't useful to anyone
+        let millis = Duration::from_millis(pause.as_millis() as u64);
+        humantime::format_duration(millis).to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pauses(scheduler: &mut Scheduler, count: usize) -> Vec<u64> {
+        (0..count)
+            .map(|_| scheduler.next_pause_at(Duration::ZERO).unwrap().as_secs())
+            .collect()
+    }
+
+    #[test]
+    fn test_fixed_backoff() {
+        let mut scheduler = Scheduler::new(Backoff::Fixed, Duration::from_secs(3), None, None);
+        assert_eq!(pauses(&mut scheduler, 4), vec![3, 3, 3, 3]);
+    }
+
+    #[test]
+    fn test_linear_backoff() {
+        let mut scheduler = Scheduler::new(
+            Backoff::Linear,
+            Duration::from_secs(2),
+            Some(Duration::from_secs(7)),
+            None,
+        );
+        assert_eq!(pauses(&mut scheduler, 5), vec![2, 4, 6, 7, 7]);
+    }
+
+    #[test]
+    fn test_exponential_backoff() {
+        let mut scheduler = Scheduler::new(
+            Backoff::Exponential,
+            Duration::from_secs(1),
+            Some(Duration::from_secs(10)),
+            None,
+        );
+        assert_eq!(pauses(&mut scheduler, 6), vec![1, 2, 4, 8, 10, 10]);
+
+        let mut uncapped = Scheduler::new(Backoff::Exponential, Duration::from_secs(1), None, None);
+        for _ in 0..100 {
+            uncapped.next_pause_at(Duration::ZERO).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_decorrelated_jitter_backoff() {
+        let pause = Duration::from_secs(1);
+        let max_pause = Duration::from_secs(20);
+        let mut scheduler =
+            Scheduler::new(Backoff::DecorrelatedJitter, pause, Some(max_pause), None);
+        let mut last = pause;
+        for _ in 0..100 {
+            let next = scheduler.next_pause_at(Duration::ZERO).unwrap();
+            assert!(next >= pause, "{:?}", next);
+            assert!(next <= max_pause, "{:?}", next);
+            assert!(next <= last * 3, "{:?} {:?}", next, last);
+            last = next;
+        }
+    }
+
+    #[test]
+    fn test_targets_get_their_own_jitter() {
+        let scheduler = Scheduler::new(
+            Backoff::DecorrelatedJitter,
+            Duration::from_millis(1),
+            None,
+            Some(Duration::from_secs(60)),
+        );
+        let mut first = scheduler.for_target(0);
+        let mut second = scheduler.for_target(1);
+        assert_eq!(first.start, second.start);
+        let pauses = |scheduler: &mut Scheduler| -> Vec<Duration> {
+            (0..5).map(|_| scheduler.calculate_pause()).collect()
+        };
+        assert_ne!(pauses(&mut first), pauses(&mut second));
+    }
+
+    #[test]
+    fn test_pause_must_fit_in_timeout() {
+        let mut scheduler = Scheduler::new(
+            Backoff::Exponential,
+            Duration::from_secs(2),
+            None,
+            Some(Duration::from_secs(10)),
+        );
+        assert_eq!(
+            scheduler.next_pause_at(Duration::from_secs(1)),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            scheduler.next_pause_at(Duration::from_secs(5)),
+            Some(Duration::from_secs(4))
+        );
+        assert_eq!(scheduler.next_pause_at(Duration::from_secs(9)), None);
+        assert_eq!(scheduler.next_pause_at(Duration::from_secs(11)), None);
+    }
+
+    #[test]
+    fn test_run_with_deadline() {
+        assert_eq!(run_with_deadline(None, || 1), Some(1));
+        assert_eq!(
+            run_with_deadline(Some(Duration::from_secs(10)), || 2),
+            Some(2)
+        );
+        assert_eq!(
+            run_with_deadline(Some(Duration::from_millis(50)), || {
+                thread::sleep(Duration::from_secs(10));
+                3
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn test_describe_pause() {
+        assert_eq!(describe_pause(Duration::from_secs(1)), "1 second");
+        assert_eq!(describe_pause(Duration::from_secs(3)), "3 seconds");
+        assert_eq!(describe_pause(Duration::from_millis(250)), "250ms");
+        assert_eq!(
+            describe_pause(Duration::from_nanos(1_500_000_001)),
+            "1s 500ms"
+        );
+    }
+}
@@ -0,0 +1,289 @@
+use crate::common::{DbError, DbErrorLifetime, DbErrorType, Opts};
+use crate::value::{Column, Row, TextKind, Value};
+use rusqlite::{types::ValueRef, Connection, ErrorCode, OpenFlags};
+use std::{
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
+
+/// The SQLite result code, including for errors the bundled SQLite can point to in the query
+pub(crate) fn error_code(e: &rusqlite::Error) -> Option<ErrorCode> {
+    match e {
+        rusqlite::Error::SqlInputError { error, .. } => Some(error.code),
+        other => other.sqlite_error_code(),
+    }
+}
+
+impl From<rusqlite::Error> for DbError {
+    fn from(e: rusqlite::Error) -> Self {
+        let message = match e {
+            rusqlite::Error::SqliteFailure(_, Some(ref message)) => message.as_str(),
+            rusqlite::Error::SqlInputError { ref msg, .. } => msg.as_str(),
+            _ => "",
+        };
+        let kind = match error_code(&e) {
+            Some(ErrorCode::NotADatabase)
+            | Some(ErrorCode::DatabaseCorrupt)
+            // a query that writes, as we only read
+            | Some(ErrorCode::ReadOnly) => DbErrorLifetime::Permanent,
+            // Syntax errors share SQLITE_ERROR with missing tables, which may just not have been created yet
+            Some(ErrorCode::Unknown) if message.contains("syntax error") => {
+                DbErrorLifetime::Permanent
+            }
+            // including the file not being there yet (SQLITE_CANTOPEN), and SQLITE_BUSY/SQLITE_LOCKED
+            Some(_) => DbErrorLifetime::Temporary,
+            None => match e {
+                rusqlite::Error::InvalidPath(_)
+                | rusqlite::Error::NulError(_)
+                | rusqlite::Error::MultipleStatement => DbErrorLifetime::Permanent,
+                _ => DbErrorLifetime::Temporary,
+            },
+        };
+        DbError {
+            kind,
+            error: DbErrorType::SqliteError { error: e },
+        }
+    }
+}
+
+// Read-only unless a `file:` URI asks for `mode=rw`, and never created, as someone else is meant to make it
+fn open_flags(connection_string: &str) -> OpenFlags {
+    let writable = connection_string.starts_with("file:")
+        && connection_string
+            .split(['?', '&'])
+            .skip(1)
+            .any(|param| param == "mode=rw");
+    let mode = if writable {
+        OpenFlags::SQLITE_OPEN_READ_WRITE
+    } else {
+        OpenFlags::SQLITE_OPEN_READ_ONLY
+    };
+    mode | OpenFlags::SQLITE_OPEN_URI | OpenFlags::SQLITE_OPEN_NO_MUTEX
+}
+
+pub fn connect(opts: &Opts) -> std::result::Result<Vec<Row>, DbError> {
+    let conn =
+        Connection::open_with_flags(&opts.connection_string, open_flags(&opts.connection_string))?;
+    if let Some(query_timeout) = opts.query_timeout {
+        conn.busy_timeout(query_timeout)?;
+    }
+    within(&conn, opts.query_timeout, || {
+        // Opening doesn't read the file, so this is what finds out if it's a database
+        conn.query_row("PRAGMA schema_version", [], |_| Ok(()))?;
+        match opts.sql_query {
+            Some(ref sql_query) => execute_statement(&conn, sql_query),
+            None => Ok(Vec::new()),
+        }
+    })
+}
+
+// SQLite has no query timeout of its own, so queries that are still going get interrupted
+fn within<T>(conn: &Connection, limit: Option<Duration>, f: impl FnOnce() -> T) -> T {
+    let limit = match limit {
+        Some(limit) => limit,
+        None => return f(),
+    };
+    let handle = conn.get_interrupt_handle();
+    let (done, finished) = mpsc::channel::<()>();
+    let watchdog = thread::spawn(move || {
+        if finished.recv_timeout(limit) == Err(mpsc::RecvTimeoutError::Timeout) {
+            handle.interrupt();
+        }
+    });
+    let result = f();
+    drop(done);
+    let _ = watchdog.join();
+    result
+}
+
+// SQLite stores dates, JSON and booleans as text or numbers, so the declared type is all there is to go on
+fn text_kind(decl_type: &str) -> TextKind {
+    let decl_type = decl_type.to_uppercase();
+    if decl_type.contains("BOOL") {
+        TextKind::Bool
+    } else if decl_type.contains("DATE") || decl_type.contains("TIME") {
+        TextKind::Timestamp
+    } else if decl_type.contains("JSON") {
+        TextKind::Json
+    } else {
+        TextKind::Text
+    }
+}
+
+fn to_value(value: ValueRef<'_>, kind: TextKind) -> Value {
+    match value {
+        ValueRef::Null => Value::Null,
+        ValueRef::Integer(i) if kind == TextKind::Bool => Value::Bool(i != 0),
+        ValueRef::Integer(i) => Value::Int(i),
+        ValueRef::Real(f) => Value::Float(f),
+        ValueRef::Text(text) => Value::parse_as(&String::from_utf8_lossy(text), kind),
+        ValueRef::Blob(bytes) => Value::Bytes(bytes.to_vec()),
+    }
+}
+
+fn execute_statement(conn: &Connection, sql_query: &str) -> Result<Vec<Row>, DbError> {
+    let mut statement = conn.prepare(sql_query)?;
+    // Expressions have no declared type
+    let columns: Arc<[Column]> = statement
+        .columns()
+        .iter()
+        .map(|col| Column {
+            name: col.name().to_string(),
+            type_name: col.decl_type().unwrap_or_default().to_string(),
+        })
+        .collect();
+    let kinds: Vec<TextKind> = columns
+        .iter()
+        .map(|col| text_kind(&col.type_name))
+        .collect();
+    let mut rows = statement.query([])?;
+    let mut results = Vec::new();
+    while let Some(row) = rows.next()? {
+        let values = kinds
+            .iter()
+            .enumerate()
+            .map(|(i, kind)| Ok(to_value(row.get_ref(i)?, *kind)))
+            .collect::<Result<Vec<Value>, rusqlite::Error>>()?;
+        results.push(Row::new(columns.clone(), values));
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::{exit_code, FailureCategory};
+    use std::path::PathBuf;
+
+    /// A database file of its own for each test, removed afterwards
+    struct TestDb(PathBuf);
+
+    impl TestDb {
+        fn new(name: &str) -> TestDb {
+            let path = std::env::temp_dir().join(format!(
+                "wait-for-db-{}-{}.db",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_file(&path);
+            TestDb(path)
+        }
+
+        fn create(name: &str) -> TestDb {
+            let db = TestDb::new(name);
+            Connection::open(&db.0)
+                .unwrap()
+                .execute_batch(
+                    "CREATE TABLE jobs (id INTEGER, name TEXT, finished DATETIME, done BOOLEAN, data BLOB);
+                     INSERT INTO jobs VALUES (1, 'migrate', '2024-01-02 03:04:05', 1, x'0001');",
+                )
+                .unwrap();
+            db
+        }
+
+        fn opts(&self) -> Opts {
+            Opts::new().connection_string(self.0.to_str().unwrap())
+        }
+    }
+
+    impl Drop for TestDb {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_sqlite_with_missing_file() {
+        let db = TestDb::new("missing");
+        let err = connect(&db.opts()).unwrap_err();
+        assert_eq!(err.kind, DbErrorLifetime::Temporary, "{:?}", err);
+        // It mustn't have been created by trying to open it
+        assert!(!db.0.exists());
+    }
+
+    #[test]
+    fn test_sqlite_with_not_a_database() {
+        let db = TestDb::new("not-a-database");
+        std::fs::write(&db.0, "This is a text file, not a database\n".repeat(50)).unwrap();
+        let err = connect(&db.opts()).unwrap_err();
+        assert_eq!(err.kind, DbErrorLifetime::Permanent, "{:?}", err);
+        assert_eq!(err.category(), FailureCategory::Config);
+        assert_eq!(err.native_code(), Some(26));
+    }
+
+    #[test]
+    fn test_sqlite_query_results() {
+        let db = TestDb::create("results");
+        let rows = connect(
+            &db.opts()
+                .sql_query("SELECT id, name, finished, done, data, 2.5 AS ratio FROM jobs"),
+        )
+        .unwrap();
+        assert_eq!(rows.len(), 1);
+        let columns: Vec<(&str, &str)> = rows[0]
+            .columns()
+            .iter()
+            .map(|c| (c.name.as_str(), c.type_name.as_str()))
+            .collect();
+        assert_eq!(
+            columns,
+            [
+                ("id", "INTEGER"),
+                ("name", "TEXT"),
+                ("finished", "DATETIME"),
+                ("done", "BOOLEAN"),
+                ("data", "BLOB"),
+                ("ratio", "")
+            ]
+        );
+        assert_eq!(rows[0]["id"], Value::Int(1));
+        assert_eq!(rows[0]["name"], Value::Text("migrate".to_string()));
+        assert_eq!(rows[0]["finished"].to_string(), "2024-01-02T03:04:05Z");
+        assert_eq!(rows[0]["done"], Value::Bool(true));
+        assert_eq!(rows[0]["data"], Value::Bytes(vec![0, 1]));
+        assert_eq!(rows[0]["ratio"], Value::Float(2.5));
+    }
+
+    #[test]
+    fn test_sqlite_query_errors() {
+        let db = TestDb::create("query-errors");
+        let err = connect(&db.opts().sql_query("SELEC 1")).unwrap_err();
+        assert_eq!(err.kind, DbErrorLifetime::Permanent, "{:?}", err);
+        assert_eq!(exit_code(&err), exitcode::DATAERR);
+
+        // e.g. migrations haven't run yet
+        let err = connect(&db.opts().sql_query("SELECT * FROM users")).unwrap_err();
+        assert_eq!(err.kind, DbErrorLifetime::Temporary, "{:?}", err);
+
+        // Read-only by default
+        let err = connect(&db.opts().sql_query("DELETE FROM jobs")).unwrap_err();
+        assert_eq!(err.kind, DbErrorLifetime::Permanent, "{:?}", err);
+        connect(
+            &Opts::new()
+                .connection_string(format!("file:{}?mode=rw", db.0.display()))
+                .sql_query("DELETE FROM jobs"),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_sqlite_when_locked() {
+        let db = TestDb::create("locked");
+        let writer = Connection::open(&db.0).unwrap();
+        writer.execute_batch("BEGIN EXCLUSIVE").unwrap();
+        let mut opts = db.opts().sql_query("SELECT * FROM jobs");
+        opts.query_timeout = Some(Duration::from_millis(100));
+        let err = connect(&opts).unwrap_err();
+        assert_eq!(err.kind, DbErrorLifetime::Temporary, "{:?}", err);
+        match err.error {
+            DbErrorType::SqliteError { ref error } => {
+                assert_eq!(error_code(error), Some(ErrorCode::DatabaseBusy))
+            }
+            _ => panic!("{:?}", err),
+        }
+
+        writer.execute_batch("COMMIT").unwrap();
+        assert_eq!(connect(&opts).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,546 @@
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use std::{
+    fmt,
+    iter::FromIterator,
+    ops::Index,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// A single column from a query, as typed as the driver lets us know about
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    /// An exact NUMERIC/DECIMAL, as text so that no digits get lost on the way through a float
+    Decimal(String),
+    Text(String),
+    Bytes(Vec<u8>),
+    Timestamp(SystemTime),
+    Json(serde_json::Value),
+}
+
+/// A column of query results, with the type the database or driver gave it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Column {
+    pub name: String,
+    /// e.g. `int8` for Postgres, `INTEGER` for ODBC or `LONGLONG` for MySQL
+    pub type_name: String,
+}
+
+/// One row of query results, with the columns in the order the query gave them
+///
+/// Column names can repeat (e.g. `SELECT a.id, b.id ...`), so lookups by name find the first one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Row {
+    columns: Arc<[Column]>,
+    values: Vec<Value>,
+}
+
+impl Row {
+    /// All the rows from a query share the same columns
+    pub fn new(columns: Arc<[Column]>, values: Vec<Value>) -> Row {
+        assert_eq!(
+            columns.len(),
+            values.len(),
+            "One value is needed per column"
+        );
+        Row { columns, values }
+    }
+
+    pub fn columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    pub fn values(&self) -> &[Value] {
+        &self.values
+    }
+
+    /// The first column with this name
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.columns
+            .iter()
+            .position(|column| column.name == name)
+            .map(|index| &self.values[index])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Column, &Value)> {
+        self.columns.iter().zip(self.values.iter())
+    }
+
+    /// Names that are unique within the row, for when they need to be keys (e.g. in JSON)
+    ///
+    /// Repeats get their position added, in the same way as target names e.g. `id` and `id-3`.
+    pub fn unique_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for (index, column) in self.columns.iter().enumerate() {
+            if names.contains(&column.name) {
+                names.push(format!("{}-{}", column.name, index + 1));
+            } else {
+                names.push(column.name.clone());
+            }
+        }
+        names
+    }
+}
+
+/// The first column with this name, e.g. `row["status"]`
+///
+/// Use [`Row::get`] for names that might not be there (e.g. ones from user input).
+///
+/// # Panics
+///
+/// If there's no column with this name.
+impl Index<&str> for Row {
+    type Output = Value;
+
+    fn index(&self, name: &str) -> &Value {
+        self.get(name)
+            .unwrap_or_else(|| panic!("No column named '{}'", name))
+    }
+}
+
+/// The column at this position
+///
+/// # Panics
+///
+/// If there are fewer columns than that. [`Row::values`] has them all.
+impl Index<usize> for Row {
+    type Output = Value;
+
+    fn index(&self, index: usize) -> &Value {
+        &self.values[index]
+    }
+}
+
+/// Rows built by hand (e.g. in tests), whose columns don't have types
+impl<S> FromIterator<(S, Value)> for Row
+where
+    S: Into<String>,
+{
+    fn from_iter<I: IntoIterator<Item = (S, Value)>>(iter: I) -> Self {
+        let (columns, values): (Vec<Column>, Vec<Value>) = iter
+            .into_iter()
+            .map(|(name, value)| {
+                (
+                    Column {
+                        name: name.into(),
+                        type_name: String::new(),
+                    },
+                    value,
+                )
+            })
+            .unzip();
+        Row::new(columns.into(), values)
+    }
+}
+
+/// A JSON object, in column order
+impl Serialize for Row {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(Some(self.values.len()))?;
+        for (name, value) in self.unique_names().iter().zip(self.values.iter()) {
+            map.serialize_entry(name, value)?;
+        }
+        map.end()
+    }
+}
+
+pub(crate) const MICROS_PER_DAY: i64 = 86_400_000_000;
+
+impl Value {
+    /// Numbers, and text that looks like one, for the `<`/`>` style expectations
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Int(i) => Some(*i as f64),
+            Value::Float(f) => Some(*f),
+            Value::Decimal(text) => text.parse::<f64>().ok(),
+            Value::Text(text) => text.trim().parse::<f64>().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        *self == Value::Null
+    }
+
+    /// Text from a driver that only gives us text, with the type it said the column was
+    #[cfg(any(feature = "odbc", feature = "mysql", feature = "sqlite"))]
+    pub(crate) fn parse_as(text: &str, kind: TextKind) -> Value {
+        let parsed = match kind {
+            TextKind::Bool => match text.trim() {
+                "1" | "t" | "true" => Some(Value::Bool(true)),
+                "0" | "f" | "false" => Some(Value::Bool(false)),
+                _ => None,
+            },
+            TextKind::Int => text.trim().parse::<i64>().ok().map(Value::Int),
+            TextKind::Float => text.trim().parse::<f64>().ok().map(Value::Float),
+            TextKind::Decimal => {
+                normalize_decimal(text).map(|_| Value::Decimal(text.trim().to_string()))
+            }
+            TextKind::Timestamp => parse_timestamp(text.trim()).map(Value::Timestamp),
+            TextKind::Bytes => parse_hex(text.trim()).map(Value::Bytes),
+            TextKind::Json => serde_json::from_str(text).ok().map(Value::Json),
+            TextKind::Text => None,
+        };
+        // Anything we can't make sense of is better as text than as nothing
+        parsed.unwrap_or_else(|| Value::Text(text.to_string()))
+    }
+}
+
+/// How to read a column that the driver hands over as text
+#[cfg(any(feature = "odbc", feature = "mysql", feature = "sqlite"))]
+#[cfg_attr(not(all(feature = "odbc", feature = "mysql")), allow(dead_code))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum TextKind {
+    Bool,
+    Int,
+    Float,
+    Decimal,
+    Text,
+    Bytes,
+    Timestamp,
+    Json,
+}
+
+/// A plain decimal (e.g. `-012.50`) in a form that's the same for equal values (e.g. `-12.5`)
+///
+/// Anything else, including exponents and `NaN`, isn't one.
+pub(crate) fn normalize_decimal(text: &str) -> Option<String> {
+    let text = text.trim();
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    if whole.len() + fraction.len() == 0
+        || !whole
+            .bytes()
+            .chain(fraction.bytes())
+            .all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
+    let whole = whole.trim_start_matches('0');
+    let fraction = fraction.trim_end_matches('0');
+    let mut normalized = String::new();
+    if negative && !(whole.is_empty() && fraction.is_empty()) {
+        normalized.push('-');
+    }
+    normalized.push_str(if whole.is_empty() { "0" } else { whole });
+    if !fraction.is_empty() {
+        normalized.push('.');
+        normalized.push_str(fraction);
+    }
+    Some(normalized)
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "NULL"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::Decimal(text) => write!(f, "{}", text),
+            Value::Text(text) => write!(f, "{}", text),
+            Value::Bytes(bytes) => write!(f, "\\x{}", to_hex(bytes)),
+            Value::Timestamp(time) => write!(f, "{}", format_timestamp(*time)),
+            Value::Json(json) => write!(f, "{}", json),
+        }
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            Value::Null => serializer.serialize_none(),
+            Value::Bool(b) => serializer.serialize_bool(*b),
+            Value::Int(i) => serializer.serialize_i64(*i),
+            Value::Float(x) => serializer.serialize_f64(*x),
+            // A JSON number would be read back as a float by most things, so keep it exact
+            Value::Decimal(text) | Value::Text(text) => serializer.serialize_str(text),
+            Value::Json(json) => json.serialize(serializer),
+            Value::Bytes(_) | Value::Timestamp(_) => serializer.serialize_str(&self.to_string()),
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// ODBC gives binary columns as hex, with or without a leading \x or 0x
+#[cfg(any(feature = "odbc", feature = "mysql", feature = "sqlite"))]
+fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    let digits = text
+        .strip_prefix("\\x")
+        .or_else(|| text.strip_prefix("0x"))
+        .unwrap_or(text);
+    if !digits.len().is_multiple_of(2) || !digits.is_ascii() {
+        return None;
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).ok())
+        .collect()
+}
+
+// Days since 1970-01-01 for a date in the proleptic Gregorian calendar
+// From http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = (i64::from(month) + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u32;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    } as u32;
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+pub(crate) fn timestamp_from_micros(micros: i64) -> Option<SystemTime> {
+    if micros >= 0 {
+        UNIX_EPOCH.checked_add(Duration::from_micros(micros as u64))
+    } else {
+        UNIX_EPOCH.checked_sub(Duration::from_micros(micros.unsigned_abs()))
+    }
+}
+
+fn micros_from_timestamp(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => after.as_micros() as i64,
+        Err(before) => -(before.duration().as_micros() as i64),
+    }
+}
+
+/// `HH:MM:SS`, with microseconds if there are any, for time of day columns
+#[cfg(any(feature = "postgres", feature = "mssql"))]
+pub(crate) fn format_time_of_day(micros: i64) -> String {
+    let seconds = micros / 1_000_000;
+    let mut text = format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    );
+    if micros % 1_000_000 > 0 {
+        text.push_str(&format!(".{:06}", micros % 1_000_000));
+    }
+    text
+}
+
+/// RFC 3339 in UTC, with microseconds if there are any
+// humantime can't do dates before 1970, which databases are quite happy with
+pub fn format_timestamp(time: SystemTime) -> String {
+    let micros = micros_from_timestamp(time);
+    let (year, month, day) = civil_from_days(micros.div_euclid(MICROS_PER_DAY));
+    let in_day = micros.rem_euclid(MICROS_PER_DAY);
+    let seconds = in_day / 1_000_000;
+    let fraction = in_day % 1_000_000;
+    let mut text = format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    );
+    if fraction > 0 {
+        text.push_str(&format!(".{:06}", fraction));
+    }
+    text.push('Z');
+    text
+}
+
+/// `YYYY-MM-DD`, optionally followed by ` HH:MM:SS[.fraction]` (or with a `T`), taken as UTC
+pub fn parse_timestamp(text: &str) -> Option<SystemTime> {
+    let text = text.strip_suffix('Z').unwrap_or(text);
+    let (date, time) = match text.split_once([' ', 'T']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (text, None),
+    };
+    let number = |s: &str| -> Option<u32> {
+        if !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) {
+            s.parse().ok()
+        } else {
+            None
+        }
+    };
+
+    let mut parts = date.splitn(3, '-');
+    let year = number(parts.next()?)?;
+    let month = number(parts.next()?)?;
+    let day = number(parts.next()?)?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let mut micros = days_from_civil(i64::from(year), month, day) * MICROS_PER_DAY;
+
+    if let Some(time) = time {
+        let (time, fraction) = match time.split_once('.') {
+            Some((time, fraction)) => (time, Some(fraction)),
+            None => (time, None),
+        };
+        let mut parts = time.splitn(3, ':');
+        let hours = number(parts.next()?)?;
+        let minutes = number(parts.next()?)?;
+        let seconds = number(parts.next()?)?;
+        if hours > 23 || minutes > 59 || seconds > 60 {
+            return None;
+        }
+        micros += i64::from(hours * 3600 + minutes * 60 + seconds) * 1_000_000;
+        if let Some(fraction) = fraction {
+            number(fraction)?;
+            // Only the first six digits fit in microseconds
+            let digits: String = fraction.chars().chain("000000".chars()).take(6).collect();
+            micros += i64::from(number(&digits)?);
+        }
+    }
+    timestamp_from_micros(micros)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_timestamps() {
+        let time = parse_timestamp("2024-02-29 13:45:07.25").unwrap();
+        assert_eq!(format_timestamp(time), "2024-02-29T13:45:07.250000Z");
+        assert_eq!(
+            format_timestamp(parse_timestamp("1969-07-20T20:17:40Z").unwrap()),
+            "1969-07-20T20:17:40Z"
+        );
+        assert_eq!(
+            parse_timestamp("1970-01-02"),
+            Some(UNIX_EPOCH + Duration::from_secs(86_400))
+        );
+        assert_eq!(parse_timestamp("0000-00-00 00:00:00"), None);
+        assert_eq!(parse_timestamp("yesterday"), None);
+    }
+
+    #[test]
+    #[cfg(any(feature = "odbc", feature = "mysql", feature = "sqlite"))]
+    fn test_parse_as() {
+        assert_eq!(Value::parse_as("42", TextKind::Int), Value::Int(42));
+        assert_eq!(Value::parse_as("1", TextKind::Bool), Value::Bool(true));
+        assert_eq!(
+            Value::parse_as("12.50", TextKind::Float),
+            Value::Float(12.5)
+        );
+        assert_eq!(
+            Value::parse_as(" 12345678901234567.89", TextKind::Decimal),
+            Value::Decimal("12345678901234567.89".to_string())
+        );
+        assert_eq!(
+            Value::parse_as("1e5", TextKind::Decimal),
+            Value::Text("1e5".to_string())
+        );
+        assert_eq!(
+            Value::parse_as("0A0b", TextKind::Bytes),
+            Value::Bytes(vec![10, 11])
+        );
+        assert_eq!(
+            Value::parse_as("{\"a\": 1}", TextKind::Json),
+            Value::Json(serde_json::json!({"a": 1}))
+        );
+        // Drivers can be vague about types, so fall back to the text
+        assert_eq!(
+            Value::parse_as("lots", TextKind::Int),
+            Value::Text("lots".to_string())
+        );
+        assert_eq!(Value::parse_as("", TextKind::Text), Value::Text("".into()));
+    }
+
+    #[test]
+    fn test_normalize_decimal() {
+        assert_eq!(normalize_decimal("12.50"), Some("12.5".to_string()));
+        assert_eq!(normalize_decimal("-0012.000"), Some("-12".to_string()));
+        assert_eq!(normalize_decimal("+.5"), Some("0.5".to_string()));
+        assert_eq!(normalize_decimal("-0.00"), Some("0".to_string()));
+        assert_eq!(normalize_decimal("1e5"), None);
+        assert_eq!(normalize_decimal("NaN"), None);
+        assert_eq!(normalize_decimal("."), None);
+    }
+
+    #[test]
+    fn test_rows_keep_order_and_duplicates() {
+        let row: Row = vec![
+            ("zebra", Value::Int(1)),
+            ("id", Value::Int(2)),
+            ("apple", Value::Null),
+            ("id", Value::Int(3)),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(row.get("id"), Some(&Value::Int(2)));
+        assert_eq!(row[3], Value::Int(3));
+        assert_eq!(row.get("missing"), None);
+        // Including when it's built into other JSON, as the output does
+        assert_eq!(
+            serde_json::json!({ "rows": [row] }).to_string(),
+            r#"{"rows":[{"zebra":1,"id":2,"apple":null,"id-4":3}]}"#
+        );
+    }
+
+    #[test]
+    fn test_display_and_json() {
+        let values = vec![
+            Value::Null,
+            Value::Bool(true),
+            Value::Int(-3),
+            Value::Float(1.5),
+            Value::Decimal("12345678901234567.89".to_string()),
+            Value::Text("done".to_string()),
+            Value::Bytes(vec![0xde, 0xad]),
+            Value::Timestamp(UNIX_EPOCH),
+            Value::Json(serde_json::json!({"ready": true})),
+        ];
+        let text: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        assert_eq!(
+            text,
+            vec![
+                "NULL",
+                "true",
+                "-3",
+                "1.5",
+                "12345678901234567.89",
+                "done",
+                "\\xdead",
+                "1970-01-01T00:00:00Z",
+                "{\"ready\":true}"
+            ]
+        );
+        assert_eq!(
+            serde_json::to_string(&values).unwrap(),
+            r#"[null,true,-3,1.5,"12345678901234567.89","done","\\xdead","1970-01-01T00:00:00Z",{"ready":true}]"#
+        );
+    }
+}